@@ -3,6 +3,7 @@ use anyhow::Result;
 use relly::buffer::{BufferPool, BufferPoolManager};
 use relly::disk::{DiskManager, PageId};
 use relly::table::{Table, UniqueIndex};
+use relly::transaction::{Transaction, TransactionManager};
 
 /* CREATE TABLE
   |id    |first_name|last_name|
@@ -16,7 +17,7 @@ use relly::table::{Table, UniqueIndex};
 fn main() -> Result<()> {
     let disk = DiskManager::open("table.rly")?;
     let pool = BufferPool::new(10);
-    let mut bufmgr = BufferPoolManager::new(disk, pool);
+    let bufmgr = BufferPoolManager::new(disk, pool);
 
     let mut table = Table {
         meta_page_id: PageId::INVALID_PAGE_ID,
@@ -26,14 +27,18 @@ fn main() -> Result<()> {
             skey: vec![2],
         }],
     };
-    table.create(&mut bufmgr)?;
+    table.create(&bufmgr)?;
     dbg!(&table);
-    table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"])?;
-    table.insert(&mut bufmgr, &[b"x", b"Bob", b"Johnson"])?;
-    table.insert(&mut bufmgr, &[b"y", b"Charlie", b"Williams"])?;
-    table.insert(&mut bufmgr, &[b"w", b"Dave", b"Miller"])?;
-    table.insert(&mut bufmgr, &[b"v", b"Eve", b"Brown"])?;
+    let txn_mgr = TransactionManager::open(&bufmgr)?;
+    let mut txn = Transaction::begin(&txn_mgr, &bufmgr)?;
+    table.insert(&bufmgr, &mut txn, &[b"z", b"Alice", b"Smith"])?;
+    table.insert(&bufmgr, &mut txn, &[b"x", b"Bob", b"Johnson"])?;
+    table.insert(&bufmgr, &mut txn, &[b"y", b"Charlie", b"Williams"])?;
+    table.insert(&bufmgr, &mut txn, &[b"w", b"Dave", b"Miller"])?;
+    table.insert(&bufmgr, &mut txn, &[b"v", b"Eve", b"Brown"])?;
+    txn.commit(&bufmgr)?;
 
-    bufmgr.flush()?;
+    bufmgr.flush_all()?;
+    println!("{}", bufmgr.stats());
     Ok(())
 }
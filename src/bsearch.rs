@@ -53,7 +53,7 @@ mod tests {
 
     #[test]
     fn test() {
-        let a = vec![1, 2, 3, 5, 8, 13, 21];
+        let a = [1, 2, 3, 5, 8, 13, 21];
         assert_eq!(Ok(0), binary_search_by(a.len(), |idx| a[idx].cmp(&1)));
         assert_eq!(Err(0), binary_search_by(a.len(), |idx| a[idx].cmp(&0)));
         assert_eq!(Ok(1), binary_search_by(a.len(), |idx| a[idx].cmp(&2)));
@@ -1,19 +1,21 @@
-use std::cell::{Ref, RefMut};
 use std::convert::identity;
-use std::rc::Rc;
+use std::ops::Bound;
+use std::sync::RwLockReadGuard;
 
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use zerocopy::{AsBytes, ByteSlice};
+use zerocopy::{ByteSlice, ByteSliceMut};
 
-use crate::buffer::{self, Buffer, BufferPoolManager};
+use crate::buffer::{self, Buffer, BufferPoolManager, Page, PageGuard};
 use crate::disk::PageId;
+use crate::mvcc::Snapshot;
 
 mod branch;
 mod leaf;
 mod meta;
 mod node;
+mod overflow;
 
 #[derive(Serialize, Deserialize)]
 pub struct Pair<'a> {
@@ -30,50 +32,158 @@ impl<'a> Pair<'a> {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+enum Value<'a> {
+    Inline(&'a [u8]),
+    Overflow { len: u64, page_id: u64 },
+}
+
+impl<'a> Value<'a> {
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::options().serialize(self).unwrap()
+    }
+    fn from_bytes(bytes: &'a [u8]) -> Self {
+        bincode::options().deserialize(bytes).unwrap()
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("duplicate key")]
     DuplicateKey,
+    #[error("key not found")]
+    KeyNotFound,
+    #[error("key too large")]
+    KeyTooLarge,
+    #[error("keys must be sorted in ascending order")]
+    UnsortedKeys,
+    #[error("tree is not empty")]
+    NotEmpty,
     #[error(transparent)]
     Buffer(#[from] buffer::Error),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Error,
+    Ignore,
+    Replace,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Inserted,
+    Ignored,
+    Replaced,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
 #[derive(Debug, Clone)]
 pub enum SearchMode {
     Start,
+    End,
     Key(Vec<u8>),
+    Range {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+    },
+}
+
+// A position between two keys, which stays meaningful while the tree changes.
+#[derive(Debug, Clone)]
+enum Anchor {
+    First,
+    Last,
+    Before(Vec<u8>),
+    After(Vec<u8>),
 }
 
 impl SearchMode {
+    fn anchor(&self, direction: Direction) -> Anchor {
+        match (self, direction) {
+            (SearchMode::Start, _) => Anchor::First,
+            (SearchMode::End, _) => Anchor::Last,
+            (SearchMode::Key(key), Direction::Forward) => Anchor::Before(key.clone()),
+            (SearchMode::Key(key), Direction::Backward) => Anchor::After(key.clone()),
+            (SearchMode::Range { start, .. }, Direction::Forward) => match start {
+                Bound::Included(key) => Anchor::Before(key.clone()),
+                Bound::Excluded(key) => Anchor::After(key.clone()),
+                Bound::Unbounded => Anchor::First,
+            },
+            (SearchMode::Range { end, .. }, Direction::Backward) => match end {
+                Bound::Included(key) => Anchor::After(key.clone()),
+                Bound::Excluded(key) => Anchor::Before(key.clone()),
+                Bound::Unbounded => Anchor::Last,
+            },
+        }
+    }
+
+    fn into_bounds(self) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        match self {
+            SearchMode::Range { start, end } => (start, end),
+            _ => (Bound::Unbounded, Bound::Unbounded),
+        }
+    }
+}
+
+impl Anchor {
     fn child_page_id(&self, branch: &branch::Branch<impl ByteSlice>) -> PageId {
         match self {
-            SearchMode::Start => branch.child_at(0),
-            SearchMode::Key(key) => branch.search_child(key),
+            Anchor::First => branch.child_at(0),
+            Anchor::Last => branch.child_at(branch.num_pairs()),
+            Anchor::Before(key) | Anchor::After(key) => branch.search_child(key),
         }
     }
 
-    fn tuple_slot_id(&self, leaf: &leaf::Leaf<impl ByteSlice>) -> Result<usize, usize> {
+    fn tuple_slot_id(&self, leaf: &leaf::Leaf<impl ByteSlice>) -> usize {
         match self {
-            SearchMode::Start => Err(0),
-            SearchMode::Key(key) => leaf.search_slot_id(key),
+            Anchor::First => 0,
+            Anchor::Last => leaf.num_pairs(),
+            Anchor::Before(key) => leaf.search_slot_id(key).unwrap_or_else(identity),
+            Anchor::After(key) => match leaf.search_slot_id(key) {
+                Ok(slot_id) => slot_id + 1,
+                Err(slot_id) => slot_id,
+            },
         }
     }
 }
 
+pub const DEFAULT_FILL_FACTOR: u8 = 90;
+const SPLIT_FILL_FACTOR: u8 = 50;
+
 pub struct BTree {
     pub meta_page_id: PageId,
 }
 
 impl BTree {
-    pub fn create(bufmgr: &mut BufferPoolManager) -> Result<Self, Error> {
+    pub fn create(bufmgr: &BufferPoolManager) -> Result<Self, Error> {
+        Self::create_with_fill_factor(bufmgr, DEFAULT_FILL_FACTOR)
+    }
+
+    pub fn create_with_fill_factor(
+        bufmgr: &BufferPoolManager,
+        fill_factor: u8,
+    ) -> Result<Self, Error> {
+        assert!((SPLIT_FILL_FACTOR..=100).contains(&fill_factor));
         let meta_buffer = bufmgr.create_page()?;
-        let mut meta = meta::Meta::new(meta_buffer.page.borrow_mut() as RefMut<[_]>);
         let root_buffer = bufmgr.create_page()?;
-        let mut root = node::Node::new(root_buffer.page.borrow_mut() as RefMut<[_]>);
-        root.initialize_as_leaf();
-        let mut leaf = leaf::Leaf::new(root.body);
-        leaf.initialize();
-        meta.header.root_page_id = root_buffer.page_id;
+        {
+            let mut meta_page = meta_buffer.page.write().unwrap();
+            let mut meta = meta::Meta::new(&mut meta_page[..]);
+            let mut root_page = root_buffer.page.write().unwrap();
+            let mut root = node::Node::new(&mut root_page[..]);
+            root.initialize_as_leaf();
+            let mut leaf = leaf::Leaf::new(root.body);
+            leaf.initialize();
+            meta.header.root_page_id = root_buffer.page_id;
+            meta.header.fill_factor = fill_factor;
+        }
+        bufmgr.log_dirty_pages()?;
         Ok(Self::new(meta_buffer.page_id))
     }
 
@@ -81,124 +191,661 @@ impl BTree {
         Self { meta_page_id }
     }
 
-    fn fetch_root_page(&self, bufmgr: &mut BufferPoolManager) -> Result<Rc<Buffer>, Error> {
+    pub fn bulk_load<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        bufmgr: &BufferPoolManager,
+        sorted_pairs: impl IntoIterator<Item = (K, V)>,
+        fill_factor: u8,
+    ) -> Result<Self, Error> {
+        let btree = Self::create_with_fill_factor(bufmgr, fill_factor)?;
+        btree.load(bufmgr, sorted_pairs, fill_factor)?;
+        Ok(btree)
+    }
+
+    // Fills an empty tree bottom-up, like `bulk_load`, for trees that were
+    // created before their contents were known. Nothing else may use the
+    // tree until the load is done.
+    pub fn load<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        sorted_pairs: impl IntoIterator<Item = (K, V)>,
+        fill_factor: u8,
+    ) -> Result<(), Error> {
+        assert!((SPLIT_FILL_FACTOR..=100).contains(&fill_factor));
+        let first_leaf_page_id = {
+            let root_buffer = self.fetch_root_page(bufmgr)?;
+            let root_page = root_buffer.page.read().unwrap();
+            let node = node::Node::new(&root_page[..]);
+            match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Leaf(leaf) if leaf.num_pairs() == 0 => root_buffer.page_id,
+                _ => return Err(Error::NotEmpty),
+            }
+        };
+        let mut level =
+            self.bulk_load_leaves(bufmgr, first_leaf_page_id, sorted_pairs, fill_factor)?;
+        while level.len() > 1 {
+            level = self.bulk_load_branches(bufmgr, level, fill_factor)?;
+        }
+        {
+            let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+            let mut meta_page = meta_buffer.page.write().unwrap();
+            let mut meta = meta::Meta::new(&mut meta_page[..]);
+            meta.header.root_page_id = level[0].1;
+            meta.header.fill_factor = fill_factor;
+            bufmgr.mark_dirty(&meta_buffer);
+        }
+        bufmgr.log_dirty_pages()?;
+        Ok(())
+    }
+
+    fn bulk_load_leaves<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        first_leaf_page_id: PageId,
+        sorted_pairs: impl IntoIterator<Item = (K, V)>,
+        fill_factor: u8,
+    ) -> Result<Vec<(Vec<u8>, PageId)>, Error> {
+        let mut leaf_buffer = bufmgr.fetch_page(first_leaf_page_id)?;
+        let mut level = vec![(vec![], first_leaf_page_id)];
+        let mut prev_key: Option<Vec<u8>> = None;
+        for (key, value) in sorted_pairs {
+            let (key, value) = (key.as_ref(), value.as_ref());
+            match prev_key.as_deref() {
+                Some(prev_key) if key == prev_key => return Err(Error::DuplicateKey),
+                Some(prev_key) if key < prev_key => return Err(Error::UnsortedKeys),
+                _ => {}
+            }
+            let new_leaf_buffer = {
+                let mut leaf_page = leaf_buffer.page.write().unwrap();
+                let leaf_node = node::Node::new(&mut leaf_page[..]);
+                let mut leaf = leaf::Leaf::new(leaf_node.body);
+                let value = self.encode_value(bufmgr, &leaf, key, value)?;
+                bufmgr.mark_dirty(&leaf_buffer);
+                if leaf.append(key, &value, fill_factor).is_some() {
+                    None
+                } else {
+                    leaf.compact();
+                    let new_leaf_buffer = bufmgr.create_page()?;
+                    leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
+                    let mut new_leaf_page = new_leaf_buffer.page.write().unwrap();
+                    let mut new_leaf_node = node::Node::new(&mut new_leaf_page[..]);
+                    new_leaf_node.initialize_as_leaf();
+                    let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
+                    new_leaf.initialize();
+                    new_leaf.set_prev_page_id(Some(leaf_buffer.page_id));
+                    new_leaf
+                        .append(key, &value, fill_factor)
+                        .expect("new leaf must have space");
+                    let prev_key = prev_key.as_deref().unwrap_or_default();
+                    level.push((shortest_separator(prev_key, key), new_leaf_buffer.page_id));
+                    Some(new_leaf_buffer.clone())
+                }
+            };
+            if let Some(new_leaf_buffer) = new_leaf_buffer {
+                leaf_buffer = new_leaf_buffer;
+                bufmgr.log_dirty_pages()?;
+            }
+            prev_key = Some(key.to_vec());
+        }
+        let mut leaf_page = leaf_buffer.page.write().unwrap();
+        let leaf_node = node::Node::new(&mut leaf_page[..]);
+        leaf::Leaf::new(leaf_node.body).compact();
+        Ok(level)
+    }
+
+    fn bulk_load_branches(
+        &self,
+        bufmgr: &BufferPoolManager,
+        children: Vec<(Vec<u8>, PageId)>,
+        fill_factor: u8,
+    ) -> Result<Vec<(Vec<u8>, PageId)>, Error> {
+        let mut level: Vec<(Vec<u8>, PageId)> = vec![];
+        let mut branch_buffer: Option<PageGuard> = None;
+        for (key, child_page_id) in children {
+            if let Some(buffer) = &branch_buffer {
+                let mut page = buffer.page.write().unwrap();
+                let node = node::Node::new(&mut page[..]);
+                let mut branch = branch::Branch::new(node.body);
+                if branch
+                    .push_child(&key, child_page_id, fill_factor)
+                    .is_some()
+                {
+                    continue;
+                }
+            }
+            bufmgr.log_dirty_pages()?;
+            let buffer = bufmgr.create_page()?;
+            {
+                let mut page = buffer.page.write().unwrap();
+                let mut node = node::Node::new(&mut page[..]);
+                node.initialize_as_branch();
+                let mut branch = branch::Branch::new(node.body);
+                branch.initialize_with_child(child_page_id);
+            }
+            level.push((key, buffer.page_id));
+            branch_buffer = Some(buffer);
+        }
+
+        // the last branch must not be left with a single child, so it takes
+        // over the rightmost child of its left sibling
+        let last_buffer = branch_buffer.expect("children must not be empty");
+        let mut last_page = last_buffer.page.write().unwrap();
+        let last_node = node::Node::new(&mut last_page[..]);
+        let mut last = branch::Branch::new(last_node.body);
+        if last.num_pairs() == 0 && level.len() > 1 {
+            let prev_buffer = bufmgr.fetch_page(level[level.len() - 2].1)?;
+            let mut prev_page = prev_buffer.page.write().unwrap();
+            let prev_node = node::Node::new(&mut prev_page[..]);
+            let mut prev = branch::Branch::new(prev_node.body);
+            let moved_child = prev.child_at(prev.num_pairs());
+            let moved_key = prev.fill_right_child();
+            let (last_key, _) = level.last_mut().unwrap();
+            let only_child = last.child_at(0);
+            last.initialize_with_child(moved_child);
+            last.push_child(last_key, only_child, 100)
+                .expect("branch must have space");
+            *last_key = moved_key;
+            bufmgr.mark_dirty(&prev_buffer);
+        }
+        Ok(level)
+    }
+
+    pub fn fill_factor(&self, bufmgr: &BufferPoolManager) -> Result<u8, Error> {
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let meta_page = meta_buffer.page.read().unwrap();
+        let meta = meta::Meta::new(&meta_page[..]);
+        Ok(match meta.header.fill_factor {
+            0 => DEFAULT_FILL_FACTOR,
+            fill_factor => fill_factor,
+        })
+    }
+
+    pub fn set_fill_factor(
+        &self,
+        bufmgr: &BufferPoolManager,
+        fill_factor: u8,
+    ) -> Result<(), Error> {
+        assert!((SPLIT_FILL_FACTOR..=100).contains(&fill_factor));
+        {
+            let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+            let mut meta_page = meta_buffer.page.write().unwrap();
+            let mut meta = meta::Meta::new(&mut meta_page[..]);
+            meta.header.fill_factor = fill_factor;
+            bufmgr.mark_dirty(&meta_buffer);
+        }
+        bufmgr.log_dirty_pages()?;
+        Ok(())
+    }
+
+    fn fetch_root_page(&self, bufmgr: &BufferPoolManager) -> Result<PageGuard, Error> {
         let root_page_id = {
             let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
-            let meta = meta::Meta::new(meta_buffer.page.borrow() as Ref<[_]>);
+            let meta_page = meta_buffer.page.read().unwrap();
+            let meta = meta::Meta::new(&meta_page[..]);
             meta.header.root_page_id
         };
         Ok(bufmgr.fetch_page(root_page_id)?)
     }
 
-    fn search_internal(
+    // Descends with shared latches, taking each child's latch before
+    // releasing its parent's, and calls `f` with the leaf still latched.
+    fn read_leaf<T>(
         &self,
-        bufmgr: &mut BufferPoolManager,
-        node_buffer: Rc<Buffer>,
-        search_mode: SearchMode,
-    ) -> Result<Iter, Error> {
-        let node = node::Node::new(node_buffer.page.borrow() as Ref<[_]>);
-        match node::Body::new(node.header.node_type, node.body.as_bytes()) {
-            node::Body::Leaf(leaf) => {
-                let slot_id = search_mode.tuple_slot_id(&leaf).unwrap_or_else(identity);
-                drop(node);
-                Ok(Iter {
-                    buffer: node_buffer,
-                    slot_id,
-                })
-            }
+        bufmgr: &BufferPoolManager,
+        anchor: &Anchor,
+        f: impl FnOnce(&PageGuard, &leaf::Leaf<&[u8]>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let meta_page = meta_buffer.page.read().unwrap();
+        let root_page_id = meta::Meta::new(&meta_page[..]).header.root_page_id;
+        let root_buffer = bufmgr.fetch_page(root_page_id)?;
+        self.read_leaf_internal(bufmgr, root_buffer, meta_page, anchor, f)
+    }
+
+    fn read_leaf_internal<T>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: PageGuard,
+        parent_page: RwLockReadGuard<'_, Page>,
+        anchor: &Anchor,
+        f: impl FnOnce(&PageGuard, &leaf::Leaf<&[u8]>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let page = buffer.page.read().unwrap();
+        drop(parent_page);
+        let node = node::Node::new(&page[..]);
+        match node::Body::new(node.header.node_type, node.body) {
+            node::Body::Leaf(leaf) => f(&buffer, &leaf),
             node::Body::Branch(branch) => {
-                let child_page_id = search_mode.child_page_id(&branch);
-                drop(node);
-                drop(node_buffer);
-                let child_node_page = bufmgr.fetch_page(child_page_id)?;
-                self.search_internal(bufmgr, child_node_page, search_mode)
+                let child_buffer = bufmgr.fetch_page(anchor.child_page_id(&branch))?;
+                self.read_leaf_internal(bufmgr, child_buffer, page, anchor, f)
             }
         }
     }
 
+    // Like `read_leaf`, but latches the leaf exclusively. The leaf cannot be
+    // split or merged meanwhile, since that needs its parent latched.
+    fn write_leaf<T>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        f: impl FnOnce(&Buffer, &mut leaf::Leaf<&mut [u8]>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let meta_page = meta_buffer.page.read().unwrap();
+        let root_page_id = meta::Meta::new(&meta_page[..]).header.root_page_id;
+        let root_buffer = bufmgr.fetch_page(root_page_id)?;
+        self.write_leaf_internal(bufmgr, root_buffer, meta_page, key, f)
+    }
+
+    fn write_leaf_internal<T>(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: PageGuard,
+        parent_page: RwLockReadGuard<'_, Page>,
+        key: &[u8],
+        f: impl FnOnce(&Buffer, &mut leaf::Leaf<&mut [u8]>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let page = buffer.page.read().unwrap();
+        let node = node::Node::new(&page[..]);
+        if let node::Body::Branch(branch) = node::Body::new(node.header.node_type, node.body) {
+            drop(parent_page);
+            let child_buffer = bufmgr.fetch_page(branch.search_child(key))?;
+            return self.write_leaf_internal(bufmgr, child_buffer, page, key, f);
+        }
+        drop(page);
+        let mut page = buffer.page.write().unwrap();
+        drop(parent_page);
+        let node = node::Node::new(&mut page[..]);
+        f(&buffer, &mut leaf::Leaf::new(node.body))
+    }
+
     pub fn search(
         &self,
-        bufmgr: &mut BufferPoolManager,
+        bufmgr: &BufferPoolManager,
         search_mode: SearchMode,
     ) -> Result<Iter, Error> {
-        let root_page = self.fetch_root_page(bufmgr)?;
-        self.search_internal(bufmgr, root_page, search_mode)
+        self.search_with_direction(bufmgr, search_mode, Direction::Forward)
     }
 
-    fn insert_internal(
+    // The iterator finds its leaf on first use, so this never fails.
+    pub fn search_with_direction(
         &self,
-        bufmgr: &mut BufferPoolManager,
-        buffer: Rc<Buffer>,
+        _bufmgr: &BufferPoolManager,
+        search_mode: SearchMode,
+        direction: Direction,
+    ) -> Result<Iter, Error> {
+        let anchor = search_mode.anchor(direction);
+        let (start, end) = search_mode.into_bounds();
+        Ok(Iter {
+            meta_page_id: self.meta_page_id,
+            anchor,
+            leaf: None,
+            start,
+            end,
+        })
+    }
+
+    pub fn get(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.read_leaf(bufmgr, &Anchor::Before(key.to_vec()), |_, leaf| match leaf
+            .search_slot_id(key)
+        {
+            Ok(slot_id) => Ok(Some(read_value(bufmgr, leaf.value_at(slot_id))?)),
+            Err(_) => Ok(None),
+        })
+    }
+
+    fn split_leaf(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: &Buffer,
+        leaf: &mut leaf::Leaf<impl ByteSliceMut>,
         key: &[u8],
         value: &[u8],
+        fill_factor: u8,
+    ) -> Result<(Vec<u8>, PageId), Error> {
+        // Latching the left neighbour while holding this leaf cannot deadlock:
+        // splits run one at a time, and nobody else holding a leaf latch waits
+        // for the latch of another leaf.
+        let prev_leaf_page_id = leaf.prev_page_id();
+        let prev_leaf_buffer = prev_leaf_page_id
+            .map(|next_leaf_page_id| bufmgr.fetch_page(next_leaf_page_id))
+            .transpose()?;
+
+        let new_leaf_buffer = bufmgr.create_page()?;
+
+        if let Some(prev_leaf_buffer) = prev_leaf_buffer {
+            let mut prev_leaf_page = prev_leaf_buffer.page.write().unwrap();
+            let node = node::Node::new(&mut prev_leaf_page[..]);
+            let mut prev_leaf = leaf::Leaf::new(node.body);
+            prev_leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
+            bufmgr.mark_dirty(&prev_leaf_buffer);
+        }
+        leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
+
+        let mut new_leaf_page = new_leaf_buffer.page.write().unwrap();
+        let mut new_leaf_node = node::Node::new(&mut new_leaf_page[..]);
+        new_leaf_node.initialize_as_leaf();
+        let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
+        new_leaf.initialize();
+        let overflow_key = leaf.split_insert(&mut new_leaf, key, value, fill_factor);
+        new_leaf.set_next_page_id(Some(buffer.page_id));
+        new_leaf.set_prev_page_id(prev_leaf_page_id);
+        bufmgr.mark_dirty(buffer);
+        Ok((overflow_key, new_leaf_buffer.page_id))
+    }
+
+    fn insert_child(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: &Buffer,
+        branch: &mut branch::Branch<impl ByteSliceMut>,
+        child_idx: usize,
+        (overflow_key_from_child, overflow_child_page_id): (Vec<u8>, PageId),
+        fill_factor: u8,
     ) -> Result<Option<(Vec<u8>, PageId)>, Error> {
-        let node = node::Node::new(buffer.page.borrow_mut() as RefMut<[_]>);
+        if branch
+            .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
+            .is_some()
+        {
+            bufmgr.mark_dirty(buffer);
+            Ok(None)
+        } else {
+            let new_branch_buffer = bufmgr.create_page()?;
+            let mut new_branch_page = new_branch_buffer.page.write().unwrap();
+            let mut new_branch_node = node::Node::new(&mut new_branch_page[..]);
+            new_branch_node.initialize_as_branch();
+            let mut new_branch = branch::Branch::new(new_branch_node.body);
+            let overflow_key = branch.split_insert(
+                &mut new_branch,
+                &overflow_key_from_child,
+                overflow_child_page_id,
+                fill_factor,
+            );
+            bufmgr.mark_dirty(buffer);
+            bufmgr.mark_dirty(&new_branch_buffer);
+            Ok(Some((overflow_key, new_branch_buffer.page_id)))
+        }
+    }
+
+    fn grow_root(
+        &self,
+        bufmgr: &BufferPoolManager,
+        meta: &mut meta::Meta<impl ByteSliceMut>,
+        key: &[u8],
+        child_page_id: PageId,
+    ) -> Result<(), Error> {
+        let root_page_id = meta.header.root_page_id;
+        let new_root_buffer = bufmgr.create_page()?;
+        let mut new_root_page = new_root_buffer.page.write().unwrap();
+        let mut node = node::Node::new(&mut new_root_page[..]);
+        node.initialize_as_branch();
+        let mut branch = branch::Branch::new(node.body);
+        branch.initialize(key, child_page_id, root_page_id);
+        meta.header.root_page_id = new_root_buffer.page_id;
+        Ok(())
+    }
+
+    fn encode_value(
+        &self,
+        bufmgr: &BufferPoolManager,
+        leaf: &leaf::Leaf<impl ByteSlice>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let inline = Value::Inline(value).to_bytes();
+        let pair = Pair {
+            key,
+            value: &inline,
+        };
+        if pair.to_bytes().len() <= leaf.max_pair_size() {
+            return Ok(inline);
+        }
+        let largest_ref = Value::Overflow {
+            len: u64::MAX,
+            page_id: u64::MAX,
+        }
+        .to_bytes();
+        let pair = Pair {
+            key,
+            value: &largest_ref,
+        };
+        if pair.to_bytes().len() > leaf.max_pair_size() {
+            return Err(Error::KeyTooLarge);
+        }
+        let page_id = write_overflow(bufmgr, value)?;
+        let overflow_ref = Value::Overflow {
+            len: value.len() as u64,
+            page_id: page_id.to_u64(),
+        };
+        Ok(overflow_ref.to_bytes())
+    }
+
+    fn replace_in_leaf(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: &Buffer,
+        leaf: &mut leaf::Leaf<impl ByteSliceMut>,
+        slot_id: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<(Vec<u8>, PageId)>, Error> {
+        let old_value = leaf.value_at(slot_id).to_vec();
+        let value = &self.encode_value(bufmgr, leaf, key, value)?;
+        bufmgr.mark_dirty(buffer);
+        let overflow = if leaf.update(slot_id, value).is_some() {
+            None
+        } else {
+            leaf.remove(slot_id);
+            Some(self.split_leaf(bufmgr, buffer, leaf, key, value, SPLIT_FILL_FACTOR)?)
+        };
+        free_value(bufmgr, &old_value)?;
+        Ok(overflow)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn insert_internal(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: PageGuard,
+        key: &[u8],
+        value: &[u8],
+        policy: ConflictPolicy,
+        append_fill_factor: Option<u8>,
+    ) -> Result<(InsertOutcome, Option<(Vec<u8>, PageId)>), Error> {
+        let mut page = buffer.page.write().unwrap();
+        let node = node::Node::new(&mut page[..]);
         match node::Body::new(node.header.node_type, node.body) {
             node::Body::Leaf(mut leaf) => {
-                let slot_id = match leaf.search_slot_id(key) {
-                    Ok(_) => return Err(Error::DuplicateKey),
-                    Err(slot_id) => slot_id,
+                let slot_id = match (leaf.search_slot_id(key), policy) {
+                    (Ok(_), ConflictPolicy::Error) => return Err(Error::DuplicateKey),
+                    (Ok(_), ConflictPolicy::Ignore) => return Ok((InsertOutcome::Ignored, None)),
+                    (Ok(slot_id), ConflictPolicy::Replace) => {
+                        let overflow =
+                            self.replace_in_leaf(bufmgr, &buffer, &mut leaf, slot_id, key, value)?;
+                        return Ok((InsertOutcome::Replaced, overflow));
+                    }
+                    (Err(slot_id), _) => slot_id,
                 };
+                let value = &self.encode_value(bufmgr, &leaf, key, value)?;
                 if leaf.insert(slot_id, key, value).is_some() {
-                    buffer.is_dirty.set(true);
-                    Ok(None)
+                    bufmgr.mark_dirty(&buffer);
+                    Ok((InsertOutcome::Inserted, None))
                 } else {
-                    let prev_leaf_page_id = leaf.prev_page_id();
-                    let prev_leaf_buffer = prev_leaf_page_id
-                        .map(|next_leaf_page_id| bufmgr.fetch_page(next_leaf_page_id))
-                        .transpose()?;
+                    let fill_factor = append_fill_factor
+                        .filter(|_| slot_id == leaf.num_pairs())
+                        .unwrap_or(SPLIT_FILL_FACTOR);
+                    let overflow =
+                        self.split_leaf(bufmgr, &buffer, &mut leaf, key, value, fill_factor)?;
+                    Ok((InsertOutcome::Inserted, Some(overflow)))
+                }
+            }
+            node::Body::Branch(mut branch) => {
+                let child_idx = branch.search_child_idx(key);
+                let child_page_id = branch.child_at(child_idx);
+                let child_node_buffer = bufmgr.fetch_page(child_page_id)?;
+                let append_fill_factor =
+                    append_fill_factor.filter(|_| child_idx == branch.num_pairs());
+                match self.insert_internal(
+                    bufmgr,
+                    child_node_buffer,
+                    key,
+                    value,
+                    policy,
+                    append_fill_factor,
+                )? {
+                    (outcome, Some(overflow_from_child)) => {
+                        let overflow = self.insert_child(
+                            bufmgr,
+                            &buffer,
+                            &mut branch,
+                            child_idx,
+                            overflow_from_child,
+                            append_fill_factor.unwrap_or(SPLIT_FILL_FACTOR),
+                        )?;
+                        Ok((outcome, overflow))
+                    }
+                    (outcome, None) => Ok((outcome, None)),
+                }
+            }
+        }
+    }
 
-                    let new_leaf_buffer = bufmgr.create_page()?;
+    pub fn insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.upsert(bufmgr, key, value, ConflictPolicy::Error)?;
+        Ok(())
+    }
 
-                    if let Some(prev_leaf_buffer) = prev_leaf_buffer {
-                        let node =
-                            node::Node::new(prev_leaf_buffer.page.borrow_mut() as RefMut<[_]>);
-                        let mut prev_leaf = leaf::Leaf::new(node.body);
-                        prev_leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
-                        prev_leaf_buffer.is_dirty.set(true);
-                    }
-                    leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
+    // Replaces the value in place, or changes nothing and returns false if the
+    // new value does not fit into the leaf.
+    fn try_replace_in_leaf(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: &Buffer,
+        leaf: &mut leaf::Leaf<impl ByteSliceMut>,
+        slot_id: usize,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<bool, Error> {
+        let old_value = leaf.value_at(slot_id).to_vec();
+        let value = self.encode_value(bufmgr, leaf, key, value)?;
+        if leaf.update(slot_id, &value).is_none() {
+            free_value(bufmgr, &value)?;
+            return Ok(false);
+        }
+        bufmgr.mark_dirty(buffer);
+        free_value(bufmgr, &old_value)?;
+        Ok(true)
+    }
 
-                    let mut new_leaf_node =
-                        node::Node::new(new_leaf_buffer.page.borrow_mut() as RefMut<[_]>);
-                    new_leaf_node.initialize_as_leaf();
-                    let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
-                    new_leaf.initialize();
-                    let overflow_key = leaf.split_insert(&mut new_leaf, key, value);
-                    new_leaf.set_next_page_id(Some(buffer.page_id));
-                    new_leaf.set_prev_page_id(prev_leaf_page_id);
-                    buffer.is_dirty.set(true);
-                    Ok(Some((overflow_key, new_leaf_buffer.page_id)))
-                }
+    // Changes only the leaf, or nothing if that would need a split, in which
+    // case it returns None.
+    fn upsert_in_leaf(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: &Buffer,
+        leaf: &mut leaf::Leaf<impl ByteSliceMut>,
+        key: &[u8],
+        value: &[u8],
+        policy: ConflictPolicy,
+    ) -> Result<Option<InsertOutcome>, Error> {
+        let slot_id = match (leaf.search_slot_id(key), policy) {
+            (Ok(_), ConflictPolicy::Error) => return Err(Error::DuplicateKey),
+            (Ok(_), ConflictPolicy::Ignore) => return Ok(Some(InsertOutcome::Ignored)),
+            (Ok(slot_id), ConflictPolicy::Replace) => {
+                let is_replaced =
+                    self.try_replace_in_leaf(bufmgr, buffer, leaf, slot_id, key, value)?;
+                return Ok(Some(InsertOutcome::Replaced).filter(|_| is_replaced));
+            }
+            (Err(slot_id), _) => slot_id,
+        };
+        let value = self.encode_value(bufmgr, leaf, key, value)?;
+        if leaf.insert(slot_id, key, &value).is_none() {
+            free_value(bufmgr, &value)?;
+            return Ok(None);
+        }
+        bufmgr.mark_dirty(buffer);
+        Ok(Some(InsertOutcome::Inserted))
+    }
+
+    // Most changes only touch a single leaf, so they are first tried with
+    // just the leaf latched exclusively. Only if the leaf has to be split is
+    // the change redone with the meta page latched exclusively, which keeps
+    // every other descent out of the tree.
+    pub fn upsert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        policy: ConflictPolicy,
+    ) -> Result<InsertOutcome, Error> {
+        let outcome = {
+            let _operation = bufmgr.begin_operation();
+            let outcome = self.write_leaf(bufmgr, key, |buffer, leaf| {
+                self.upsert_in_leaf(bufmgr, buffer, leaf, key, value, policy)
+            })?;
+            match outcome {
+                Some(outcome) => outcome,
+                None => self.upsert_with_split(bufmgr, key, value, policy)?,
+            }
+        };
+        bufmgr.log_dirty_pages()?;
+        Ok(outcome)
+    }
+
+    fn upsert_with_split(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        policy: ConflictPolicy,
+    ) -> Result<InsertOutcome, Error> {
+        let fill_factor = self.fill_factor(bufmgr)?;
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let mut meta_page = meta_buffer.page.write().unwrap();
+        let mut meta = meta::Meta::new(&mut meta_page[..]);
+        let root_buffer = bufmgr.fetch_page(meta.header.root_page_id)?;
+        let (outcome, overflow) =
+            self.insert_internal(bufmgr, root_buffer, key, value, policy, Some(fill_factor))?;
+        if let Some((key, child_page_id)) = overflow {
+            self.grow_root(bufmgr, &mut meta, &key, child_page_id)?;
+            bufmgr.mark_dirty(&meta_buffer);
+        }
+        Ok(outcome)
+    }
+
+    fn update_internal(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: PageGuard,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<(Vec<u8>, PageId)>, Error> {
+        let mut page = buffer.page.write().unwrap();
+        let node = node::Node::new(&mut page[..]);
+        match node::Body::new(node.header.node_type, node.body) {
+            node::Body::Leaf(mut leaf) => {
+                let slot_id = leaf.search_slot_id(key).or(Err(Error::KeyNotFound))?;
+                self.replace_in_leaf(bufmgr, &buffer, &mut leaf, slot_id, key, value)
             }
             node::Body::Branch(mut branch) => {
                 let child_idx = branch.search_child_idx(key);
                 let child_page_id = branch.child_at(child_idx);
                 let child_node_buffer = bufmgr.fetch_page(child_page_id)?;
-                if let Some((overflow_key_from_child, overflow_child_page_id)) =
-                    self.insert_internal(bufmgr, child_node_buffer, key, value)?
+                if let Some(overflow_from_child) =
+                    self.update_internal(bufmgr, child_node_buffer, key, value)?
                 {
-                    if branch
-                        .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
-                        .is_some()
-                    {
-                        buffer.is_dirty.set(true);
-                        Ok(None)
-                    } else {
-                        let new_branch_buffer = bufmgr.create_page()?;
-                        let mut new_branch_node =
-                            node::Node::new(new_branch_buffer.page.borrow_mut() as RefMut<[_]>);
-                        new_branch_node.initialize_as_branch();
-                        let mut new_branch = branch::Branch::new(new_branch_node.body);
-                        let overflow_key = branch.split_insert(
-                            &mut new_branch,
-                            &overflow_key_from_child,
-                            overflow_child_page_id,
-                        );
-                        buffer.is_dirty.set(true);
-                        new_branch_buffer.is_dirty.set(true);
-                        Ok(Some((overflow_key, new_branch_buffer.page_id)))
-                    }
+                    self.insert_child(
+                        bufmgr,
+                        &buffer,
+                        &mut branch,
+                        child_idx,
+                        overflow_from_child,
+                        SPLIT_FILL_FACTOR,
+                    )
                 } else {
                     Ok(None)
                 }
@@ -206,65 +853,959 @@ impl BTree {
         }
     }
 
-    pub fn insert(
+    pub fn update(
+        &self,
+        bufmgr: &BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        {
+            let _operation = bufmgr.begin_operation();
+            let is_replaced = self.write_leaf(bufmgr, key, |buffer, leaf| {
+                let slot_id = leaf.search_slot_id(key).or(Err(Error::KeyNotFound))?;
+                self.try_replace_in_leaf(bufmgr, buffer, leaf, slot_id, key, value)
+            })?;
+            if !is_replaced {
+                self.update_with_split(bufmgr, key, value)?;
+            }
+        }
+        bufmgr.log_dirty_pages()?;
+        Ok(())
+    }
+
+    fn update_with_split(
         &self,
-        bufmgr: &mut BufferPoolManager,
+        bufmgr: &BufferPoolManager,
         key: &[u8],
         value: &[u8],
     ) -> Result<(), Error> {
         let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
-        let mut meta = meta::Meta::new(meta_buffer.page.borrow_mut() as RefMut<[_]>);
+        let mut meta_page = meta_buffer.page.write().unwrap();
+        let mut meta = meta::Meta::new(&mut meta_page[..]);
+        let root_buffer = bufmgr.fetch_page(meta.header.root_page_id)?;
+        if let Some((key, child_page_id)) = self.update_internal(bufmgr, root_buffer, key, value)? {
+            self.grow_root(bufmgr, &mut meta, &key, child_page_id)?;
+            bufmgr.mark_dirty(&meta_buffer);
+        }
+        Ok(())
+    }
+
+    fn delete_internal(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: PageGuard,
+        key: &[u8],
+    ) -> Result<bool, Error> {
+        let mut page = buffer.page.write().unwrap();
+        let node = node::Node::new(&mut page[..]);
+        match node::Body::new(node.header.node_type, node.body) {
+            node::Body::Leaf(mut leaf) => {
+                let slot_id = leaf.search_slot_id(key).or(Err(Error::KeyNotFound))?;
+                let value = leaf.value_at(slot_id).to_vec();
+                leaf.remove(slot_id);
+                bufmgr.mark_dirty(&buffer);
+                free_value(bufmgr, &value)?;
+                Ok(!leaf.is_half_full())
+            }
+            node::Body::Branch(mut branch) => {
+                let child_idx = branch.search_child_idx(key);
+                let child_page_id = branch.child_at(child_idx);
+                let child_node_buffer = bufmgr.fetch_page(child_page_id)?;
+                // a branch left with a single child has no sibling to
+                // rebalance it with; being underfull itself, it is merged or
+                // refilled by its own parent instead
+                if self.delete_internal(bufmgr, child_node_buffer, key)? && branch.num_pairs() > 0 {
+                    let sep_slot_id = if child_idx == branch.num_pairs() {
+                        child_idx - 1
+                    } else {
+                        child_idx
+                    };
+                    let merged_page_id =
+                        self.rebalance_children(bufmgr, &mut branch, sep_slot_id)?;
+                    bufmgr.mark_dirty(&buffer);
+                    if let Some(page_id) = merged_page_id {
+                        bufmgr.delete_page(page_id)?;
+                    }
+                }
+                Ok(!branch.is_half_full())
+            }
+        }
+    }
+
+    fn rebalance_children(
+        &self,
+        bufmgr: &BufferPoolManager,
+        branch: &mut branch::Branch<impl ByteSliceMut>,
+        sep_slot_id: usize,
+    ) -> Result<Option<PageId>, Error> {
+        let sep_key = branch.pair_at(sep_slot_id).key.to_vec();
+        let left_buffer = bufmgr.fetch_page(branch.child_at(sep_slot_id))?;
+        let right_buffer = bufmgr.fetch_page(branch.child_at(sep_slot_id + 1))?;
+        let mut left_page = left_buffer.page.write().unwrap();
+        let mut right_page = right_buffer.page.write().unwrap();
+        bufmgr.mark_dirty(&left_buffer);
+        bufmgr.mark_dirty(&right_buffer);
+        let left_image = *left_page;
+        let right_image = *right_page;
+        let new_sep_key = {
+            let left_node = node::Node::new(&mut left_page[..]);
+            let right_node = node::Node::new(&mut right_page[..]);
+            match (
+                node::Body::new(left_node.header.node_type, left_node.body),
+                node::Body::new(right_node.header.node_type, right_node.body),
+            ) {
+                (node::Body::Leaf(mut left), node::Body::Leaf(mut right)) => {
+                    if left.merge(&right).is_some() {
+                        let next_leaf_page_id = right.next_page_id();
+                        left.set_next_page_id(next_leaf_page_id);
+                        if let Some(next_leaf_page_id) = next_leaf_page_id {
+                            let next_leaf_buffer = bufmgr.fetch_page(next_leaf_page_id)?;
+                            let mut next_leaf_page = next_leaf_buffer.page.write().unwrap();
+                            let node = node::Node::new(&mut next_leaf_page[..]);
+                            let mut next_leaf = leaf::Leaf::new(node.body);
+                            next_leaf.set_prev_page_id(Some(left_buffer.page_id));
+                            bufmgr.mark_dirty(&next_leaf_buffer);
+                        }
+                        branch.remove(sep_slot_id);
+                        return Ok(Some(right_buffer.page_id));
+                    }
+                    // siblings that cannot be evened out stay underfull,
+                    // which is still a valid tree
+                    if left.redistribute(&mut right).is_none() {
+                        return Ok(None);
+                    }
+                    let left_last_key = left.key_at(left.num_pairs() - 1);
+                    shortest_separator(&left_last_key, &right.key_at(0))
+                }
+                (node::Body::Branch(mut left), node::Body::Branch(mut right)) => {
+                    if left.can_merge(&right, &sep_key) {
+                        left.merge(&mut right, &sep_key);
+                        branch.remove(sep_slot_id);
+                        return Ok(Some(right_buffer.page_id));
+                    }
+                    if left.is_half_full() {
+                        left.lend_to_right(&mut right, &sep_key)
+                    } else {
+                        left.borrow_from_right(&mut right, &sep_key)
+                    }
+                }
+                _ => unreachable!("siblings must be at the same level"),
+            }
+        };
+        // A longer separator may not fit into a full parent. The children are
+        // then put back as they were, since an underfull node is still valid.
+        if branch.set_key_at(sep_slot_id, &new_sep_key).is_none() {
+            *left_page = left_image;
+            *right_page = right_image;
+        }
+        Ok(None)
+    }
+
+    // Deletes always hold the meta page exclusively, since merges latch
+    // siblings in an order that is only safe without concurrent splits.
+    pub fn delete(&self, bufmgr: &BufferPoolManager, key: &[u8]) -> Result<(), Error> {
+        let operation = bufmgr.begin_operation();
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let mut meta_page = meta_buffer.page.write().unwrap();
+        let mut meta = meta::Meta::new(&mut meta_page[..]);
         let root_page_id = meta.header.root_page_id;
         let root_buffer = bufmgr.fetch_page(root_page_id)?;
-        if let Some((key, child_page_id)) = self.insert_internal(bufmgr, root_buffer, key, value)? {
-            let new_root_buffer = bufmgr.create_page()?;
-            let mut node = node::Node::new(new_root_buffer.page.borrow_mut() as RefMut<[_]>);
-            node.initialize_as_branch();
-            let mut branch = branch::Branch::new(node.body);
-            branch.initialize(&key, child_page_id, root_page_id);
-            meta.header.root_page_id = new_root_buffer.page_id;
-            meta_buffer.is_dirty.set(true);
+        self.delete_internal(bufmgr, root_buffer.clone(), key)?;
+        let collapsed = {
+            let root_page = root_buffer.page.read().unwrap();
+            let node = node::Node::new(&root_page[..]);
+            match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Branch(branch) if branch.num_pairs() == 0 => {
+                    meta.header.root_page_id = branch.child_at(0);
+                    bufmgr.mark_dirty(&meta_buffer);
+                    true
+                }
+                _ => false,
+            }
+        };
+        drop(root_buffer);
+        if collapsed {
+            bufmgr.delete_page(root_page_id)?;
         }
+        drop(meta_page);
+        drop(operation);
+        bufmgr.log_dirty_pages()?;
         Ok(())
     }
 }
 
+fn shortest_separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+    debug_assert!(left < right);
+    let common_len = left.iter().zip(right).take_while(|(l, r)| l == r).count();
+    right[..common_len + 1].to_vec()
+}
+
+fn write_overflow(bufmgr: &BufferPoolManager, data: &[u8]) -> Result<PageId, Error> {
+    let mut next_page_id = None;
+    for chunk in data.chunks(overflow::MAX_DATA_SIZE).rev() {
+        let buffer = bufmgr.create_page()?;
+        {
+            let mut page = buffer.page.write().unwrap();
+            let mut overflow = overflow::Overflow::new(&mut page[..]);
+            overflow.initialize();
+            overflow.set_next_page_id(next_page_id);
+            overflow.set_data(chunk);
+        }
+        // logging the chain as it grows lets values larger than the buffer
+        // pool be written
+        bufmgr.log_unlinked_page(&buffer)?;
+        next_page_id = Some(buffer.page_id);
+    }
+    Ok(next_page_id.expect("overflow data must not be empty"))
+}
+
+fn read_value(bufmgr: &BufferPoolManager, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    let (len, page_id) = match Value::from_bytes(bytes) {
+        Value::Inline(value) => return Ok(value.to_vec()),
+        Value::Overflow { len, page_id } => (len, PageId(page_id)),
+    };
+    let mut value = Vec::with_capacity(len as usize);
+    let mut next_page_id = Some(page_id);
+    while let Some(page_id) = next_page_id {
+        let buffer = bufmgr.fetch_page(page_id)?;
+        let page = buffer.page.read().unwrap();
+        let overflow = overflow::Overflow::new(&page[..]);
+        value.extend_from_slice(overflow.data());
+        next_page_id = overflow.next_page_id();
+    }
+    Ok(value)
+}
+
+fn free_value(bufmgr: &BufferPoolManager, bytes: &[u8]) -> Result<(), Error> {
+    let mut next_page_id = match Value::from_bytes(bytes) {
+        Value::Inline(_) => return Ok(()),
+        Value::Overflow { page_id, .. } => Some(PageId(page_id)),
+    };
+    while let Some(page_id) = next_page_id {
+        next_page_id = {
+            let buffer = bufmgr.fetch_page(page_id)?;
+            let page = buffer.page.read().unwrap();
+            let overflow = overflow::Overflow::new(&page[..]);
+            overflow.next_page_id()
+        };
+        bufmgr.delete_page(page_id)?;
+    }
+    Ok(())
+}
+
+enum Step {
+    Found(PageGuard, Vec<u8>, Vec<u8>),
+    OutOfRange,
+    Sibling(PageId, Option<PageId>),
+}
+
+// The iterator keeps no latch between calls. It remembers its position as a
+// key and the leaf it was last in, and descends from the root again whenever
+// that leaf may no longer hold the keys next to the position.
 pub struct Iter {
-    buffer: Rc<Buffer>,
-    slot_id: usize,
+    meta_page_id: PageId,
+    anchor: Anchor,
+    // the leaf, and the sibling it was entered from, if any
+    leaf: Option<(PageGuard, Option<PageId>)>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
 }
 
 impl Iter {
-    fn get(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        let leaf_node = node::Node::new(self.buffer.page.borrow() as Ref<[_]>);
-        let leaf = leaf::Leaf::new(leaf_node.body);
-        if self.slot_id < leaf.num_pairs() {
-            let pair = leaf.pair_at(self.slot_id);
-            Some((pair.key.to_vec(), pair.value.to_vec()))
-        } else {
-            None
+    fn is_before_start(&self, key: &[u8]) -> bool {
+        match &self.start {
+            Bound::Included(start) => key < start.as_slice(),
+            Bound::Excluded(start) => key <= start.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn is_past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(end) => key > end.as_slice(),
+            Bound::Excluded(end) => key >= end.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    // A leaf entered from a sibling is still right if the two are still
+    // linked; otherwise the leaf must still hold the key at the position.
+    fn is_valid_leaf(
+        &self,
+        leaf: &leaf::Leaf<&[u8]>,
+        from_page_id: Option<PageId>,
+        direction: Direction,
+    ) -> bool {
+        match (from_page_id, direction) {
+            (Some(page_id), Direction::Forward) => leaf.prev_page_id() == Some(page_id),
+            (Some(page_id), Direction::Backward) => leaf.next_page_id() == Some(page_id),
+            (None, _) => match &self.anchor {
+                Anchor::First => leaf.prev_page_id().is_none(),
+                Anchor::Last => leaf.next_page_id().is_none(),
+                Anchor::Before(key) | Anchor::After(key) => leaf.search_slot_id(key).is_ok(),
+            },
+        }
+    }
+
+    fn step(
+        &self,
+        bufmgr: &BufferPoolManager,
+        buffer: &PageGuard,
+        leaf: &leaf::Leaf<&[u8]>,
+        direction: Direction,
+    ) -> Result<Step, Error> {
+        let slot_id = self.anchor.tuple_slot_id(leaf);
+        let (slot_id, sibling_page_id) = match direction {
+            Direction::Forward => (
+                Some(slot_id).filter(|&slot_id| slot_id < leaf.num_pairs()),
+                leaf.next_page_id(),
+            ),
+            Direction::Backward => (slot_id.checked_sub(1), leaf.prev_page_id()),
+        };
+        let slot_id = match slot_id {
+            Some(slot_id) => slot_id,
+            None => return Ok(Step::Sibling(buffer.page_id, sibling_page_id)),
+        };
+        let key = leaf.key_at(slot_id);
+        let is_out_of_range = match direction {
+            Direction::Forward => self.is_past_end(&key),
+            Direction::Backward => self.is_before_start(&key),
+        };
+        if is_out_of_range {
+            return Ok(Step::OutOfRange);
+        }
+        // overflow pages are only freed with the leaf latched exclusively
+        let value = read_value(bufmgr, leaf.value_at(slot_id))?;
+        Ok(Step::Found(buffer.clone(), key, value))
+    }
+
+    fn step_in_leaf(
+        &self,
+        bufmgr: &BufferPoolManager,
+        direction: Direction,
+    ) -> Result<Option<Step>, Error> {
+        let (buffer, from_page_id) = match &self.leaf {
+            Some(leaf) => leaf,
+            None => return Ok(None),
+        };
+        let page = buffer.page.read().unwrap();
+        let node = node::Node::new(&page[..]);
+        match node::Body::new(node.header.node_type, node.body) {
+            node::Body::Leaf(leaf) if self.is_valid_leaf(&leaf, *from_page_id, direction) => {
+                Ok(Some(self.step(bufmgr, buffer, &leaf, direction)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn advance(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        direction: Direction,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        loop {
+            let step = match self.step_in_leaf(bufmgr, direction)? {
+                Some(step) => step,
+                None => BTree::new(self.meta_page_id).read_leaf(
+                    bufmgr,
+                    &self.anchor,
+                    |buffer, leaf| self.step(bufmgr, buffer, leaf, direction),
+                )?,
+            };
+            match step {
+                Step::Found(buffer, key, value) => {
+                    self.anchor = match direction {
+                        Direction::Forward => Anchor::After(key.clone()),
+                        Direction::Backward => Anchor::Before(key.clone()),
+                    };
+                    self.leaf = Some((buffer, None));
+                    return Ok(Some((key, value)));
+                }
+                Step::OutOfRange | Step::Sibling(_, None) => return Ok(None),
+                Step::Sibling(page_id, Some(sibling_page_id)) => {
+                    let buffer = bufmgr.fetch_page(sibling_page_id)?;
+                    self.leaf = Some((buffer, Some(page_id)));
+                }
+            }
         }
     }
 
     #[allow(clippy::type_complexity)]
     pub fn next(
         &mut self,
-        bufmgr: &mut BufferPoolManager,
+        bufmgr: &BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        self.advance(bufmgr, Direction::Forward)
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn prev(
+        &mut self,
+        bufmgr: &BufferPoolManager,
     ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
-        let value = self.get();
-        self.slot_id += 1;
-        let next_page_id = {
-            let leaf_node = node::Node::new(self.buffer.page.borrow() as Ref<[_]>);
-            let leaf = leaf::Leaf::new(leaf_node.body);
-            if self.slot_id < leaf.num_pairs() {
-                return Ok(value);
+        self.advance(bufmgr, Direction::Backward)
+    }
+
+    // Skips keys without a version visible to the snapshot and returns the
+    // visible version of the value. Only valid on trees of versioned values.
+    #[allow(clippy::type_complexity)]
+    pub fn advance_visible(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        direction: Direction,
+        snapshot: &Snapshot,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        while let Some((key, bytes)) = self.advance(bufmgr, direction)? {
+            if let Some(value) = snapshot.visible_value(&bytes) {
+                return Ok(Some((key, value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::{tempdir, tempfile};
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+
+    fn setup(pool_size: usize) -> BufferPoolManager {
+        let disk = DiskManager::new(tempfile().unwrap(), tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(pool_size);
+        BufferPoolManager::new(disk, pool)
+    }
+
+    fn collect(btree: &BTree, bufmgr: &BufferPoolManager) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut iter = btree.search(bufmgr, SearchMode::Start).unwrap();
+        let mut pairs = vec![];
+        while let Some(pair) = iter.next(bufmgr).unwrap() {
+            pairs.push(pair);
+        }
+        pairs
+    }
+
+    fn key(i: u64) -> Vec<u8> {
+        let mut key = i.to_be_bytes().to_vec();
+        key.resize(200, 0);
+        key
+    }
+
+    #[test]
+    fn test_delete() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let value = vec![0xaa; 256];
+        for i in 0u64..1000 {
+            btree.insert(&bufmgr, &key(i), &value).unwrap();
+        }
+        for i in (0u64..1000).filter(|i| i % 3 != 0) {
+            btree.delete(&bufmgr, &key(i)).unwrap();
+        }
+        assert!(matches!(
+            btree.delete(&bufmgr, &key(1)),
+            Err(Error::KeyNotFound)
+        ));
+
+        let keys: Vec<_> = collect(&btree, &bufmgr)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<_> = (0u64..1000).filter(|i| i % 3 == 0).map(key).collect();
+        assert_eq!(expected, keys);
+
+        for i in (0u64..1000).filter(|i| i % 3 == 0).rev() {
+            btree.delete(&bufmgr, &key(i)).unwrap();
+        }
+        assert!(collect(&btree, &bufmgr).is_empty());
+
+        btree.insert(&bufmgr, b"hello", b"world").unwrap();
+        assert_eq!(
+            vec![(b"hello".to_vec(), b"world".to_vec())],
+            collect(&btree, &bufmgr)
+        );
+    }
+
+    // Long separators leave branches with only a few keys, down to a single
+    // child, and may not fit into a parent in place of shorter ones.
+    #[test]
+    fn test_delete_large_keys() {
+        let bufmgr = setup(100);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut rand = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut keys = vec![];
+        for i in 0u64..600 {
+            let mut key = i.to_be_bytes().to_vec();
+            let len = if rand() % 3 == 0 {
+                1000 + rand() % 900
+            } else {
+                8
+            };
+            key.resize(len as usize, 0xcc);
+            btree.insert(&bufmgr, &key, b"v").unwrap();
+            keys.push(key);
+        }
+        for i in (1..keys.len()).rev() {
+            keys.swap(i, rand() as usize % (i + 1));
+        }
+        let (deleted, kept) = keys.split_at(400);
+        for key in deleted {
+            btree.delete(&bufmgr, key).unwrap();
+        }
+        let mut expected = kept.to_vec();
+        expected.sort();
+        let remaining: Vec<_> = collect(&btree, &bufmgr)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(expected, remaining);
+        for key in kept {
+            btree.delete(&bufmgr, key).unwrap();
+        }
+        assert!(collect(&btree, &bufmgr).is_empty());
+    }
+
+    #[test]
+    fn test_page_reuse() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let value = vec![0xaa; 256];
+        for i in 0u64..1000 {
+            btree.insert(&bufmgr, &key(i), &value).unwrap();
+        }
+        let high_page_id = bufmgr.create_page().unwrap().page_id;
+        bufmgr.delete_page(high_page_id).unwrap();
+
+        for round in 0..3 {
+            for i in 0u64..1000 {
+                btree.delete(&bufmgr, &key(i)).unwrap();
+            }
+            for i in 0u64..1000 {
+                btree.insert(&bufmgr, &key(i), &value).unwrap();
+            }
+            let page_id = bufmgr.create_page().unwrap().page_id;
+            assert!(
+                page_id.to_u64() <= high_page_id.to_u64() + 1,
+                "round {}",
+                round
+            );
+            bufmgr.delete_page(page_id).unwrap();
+        }
+        assert_eq!(1000, collect(&btree, &bufmgr).len());
+    }
+
+    #[test]
+    fn test_recovery() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&bufmgr).unwrap();
+        let value = vec![0xaa; 256];
+        for i in 0u64..500 {
+            btree.insert(&bufmgr, &key(i), &value).unwrap();
+        }
+        for i in (0u64..500).filter(|i| i % 2 == 0) {
+            btree.delete(&bufmgr, &key(i)).unwrap();
+        }
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::new(btree.meta_page_id);
+        let keys: Vec<_> = collect(&btree, &bufmgr)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<_> = (0u64..500).filter(|i| i % 2 == 1).map(key).collect();
+        assert_eq!(expected, keys);
+    }
+
+    #[test]
+    fn test_update() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in 0u64..100 {
+            btree.insert(&bufmgr, &key(i), b"small").unwrap();
+        }
+        let large_value = vec![0xbb; 1000];
+        for i in (0u64..100).step_by(2) {
+            btree.update(&bufmgr, &key(i), &large_value).unwrap();
+        }
+        assert!(matches!(
+            btree.update(&bufmgr, &key(100), b"missing"),
+            Err(Error::KeyNotFound)
+        ));
+
+        let pairs = collect(&btree, &bufmgr);
+        assert_eq!(100, pairs.len());
+        for (i, (k, v)) in pairs.into_iter().enumerate() {
+            assert_eq!(key(i as u64), k);
+            if i % 2 == 0 {
+                assert_eq!(large_value, v);
+            } else {
+                assert_eq!(b"small".to_vec(), v);
+            }
+        }
+    }
+
+    #[test]
+    fn test_upsert() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        assert_eq!(
+            InsertOutcome::Inserted,
+            btree
+                .upsert(&bufmgr, b"hello", b"world", ConflictPolicy::Error)
+                .unwrap()
+        );
+        assert!(matches!(
+            btree.upsert(&bufmgr, b"hello", b"!", ConflictPolicy::Error),
+            Err(Error::DuplicateKey)
+        ));
+        assert_eq!(
+            InsertOutcome::Ignored,
+            btree
+                .upsert(&bufmgr, b"hello", b"!", ConflictPolicy::Ignore)
+                .unwrap()
+        );
+        assert_eq!(
+            vec![(b"hello".to_vec(), b"world".to_vec())],
+            collect(&btree, &bufmgr)
+        );
+        assert_eq!(
+            InsertOutcome::Replaced,
+            btree
+                .upsert(&bufmgr, b"hello", b"!", ConflictPolicy::Replace)
+                .unwrap()
+        );
+        assert_eq!(
+            vec![(b"hello".to_vec(), b"!".to_vec())],
+            collect(&btree, &bufmgr)
+        );
+    }
+
+    #[test]
+    fn test_search_range() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in (0u64..1000).step_by(2) {
+            btree.insert(&bufmgr, &key(i), b"").unwrap();
+        }
+        let scan = |bufmgr: &BufferPoolManager, start, end| {
+            let mut iter = btree
+                .search(bufmgr, SearchMode::Range { start, end })
+                .unwrap();
+            let mut keys = vec![];
+            while let Some((k, _)) = iter.next(bufmgr).unwrap() {
+                keys.push(k);
             }
-            leaf.next_page_id()
+            keys
+        };
+        let expected: Vec<_> = (100u64..=200).step_by(2).map(key).collect();
+        assert_eq!(
+            expected,
+            scan(
+                &bufmgr,
+                Bound::Included(key(100)),
+                Bound::Included(key(200))
+            )
+        );
+        let expected: Vec<_> = (102u64..200).step_by(2).map(key).collect();
+        assert_eq!(
+            expected,
+            scan(
+                &bufmgr,
+                Bound::Excluded(key(100)),
+                Bound::Excluded(key(200))
+            )
+        );
+        let expected: Vec<_> = (0u64..=10).step_by(2).map(key).collect();
+        assert_eq!(
+            expected,
+            scan(&bufmgr, Bound::Unbounded, Bound::Included(key(11)))
+        );
+        let expected: Vec<_> = (990u64..1000).step_by(2).map(key).collect();
+        assert_eq!(
+            expected,
+            scan(&bufmgr, Bound::Included(key(989)), Bound::Unbounded)
+        );
+    }
+
+    #[test]
+    fn test_search_backward() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        for i in (0u64..1000).step_by(2) {
+            btree.insert(&bufmgr, &key(i), b"").unwrap();
+        }
+        let scan = |bufmgr: &BufferPoolManager, search_mode| {
+            let mut iter = btree
+                .search_with_direction(bufmgr, search_mode, Direction::Backward)
+                .unwrap();
+            let mut keys = vec![];
+            while let Some((k, _)) = iter.prev(bufmgr).unwrap() {
+                keys.push(k);
+            }
+            keys
+        };
+        let expected: Vec<_> = (0u64..1000).rev().filter(|i| i % 2 == 0).map(key).collect();
+        assert_eq!(expected, scan(&bufmgr, SearchMode::End));
+        let expected: Vec<_> = (0u64..=500).rev().filter(|i| i % 2 == 0).map(key).collect();
+        assert_eq!(expected, scan(&bufmgr, SearchMode::Key(key(500))));
+        let expected: Vec<_> = (102u64..=200)
+            .rev()
+            .filter(|i| i % 2 == 0)
+            .map(key)
+            .collect();
+        assert_eq!(
+            expected,
+            scan(
+                &bufmgr,
+                SearchMode::Range {
+                    start: Bound::Excluded(key(100)),
+                    end: Bound::Included(key(201)),
+                }
+            )
+        );
+
+        let mut iter = btree.search(&bufmgr, SearchMode::Key(key(100))).unwrap();
+        assert_eq!(key(100), iter.next(&bufmgr).unwrap().unwrap().0);
+        assert_eq!(key(100), iter.prev(&bufmgr).unwrap().unwrap().0);
+        assert_eq!(key(98), iter.prev(&bufmgr).unwrap().unwrap().0);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let large_value: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        btree.insert(&bufmgr, b"large", &large_value).unwrap();
+        btree.insert(&bufmgr, b"small", b"value").unwrap();
+        assert_eq!(
+            vec![
+                (b"large".to_vec(), large_value.clone()),
+                (b"small".to_vec(), b"value".to_vec())
+            ],
+            collect(&btree, &bufmgr)
+        );
+
+        btree.update(&bufmgr, b"small", &large_value).unwrap();
+        btree.update(&bufmgr, b"large", b"value").unwrap();
+        assert_eq!(
+            vec![
+                (b"large".to_vec(), b"value".to_vec()),
+                (b"small".to_vec(), large_value)
+            ],
+            collect(&btree, &bufmgr)
+        );
+
+        assert!(matches!(
+            btree.insert(&bufmgr, &[0xff; 3000], b"value"),
+            Err(Error::KeyTooLarge)
+        ));
+    }
+
+    // Overflow pages are logged as they are written, so the chain does not
+    // have to fit into the pool at once.
+    #[test]
+    fn test_value_larger_than_pool() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&bufmgr).unwrap();
+        let large_value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        btree.insert(&bufmgr, b"large", &large_value).unwrap();
+        btree.insert(&bufmgr, b"small", b"value").unwrap();
+        let larger_value: Vec<u8> = (0..150_000u32).map(|i| (i / 3) as u8).collect();
+        btree.update(&bufmgr, b"small", &larger_value).unwrap();
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::new(btree.meta_page_id);
+        assert_eq!(
+            vec![
+                (b"large".to_vec(), large_value),
+                (b"small".to_vec(), larger_value)
+            ],
+            collect(&btree, &bufmgr)
+        );
+        btree.delete(&bufmgr, b"large").unwrap();
+        btree.delete(&bufmgr, b"small").unwrap();
+        assert!(collect(&btree, &bufmgr).is_empty());
+    }
+
+    #[test]
+    fn test_large_keys_small_pool() {
+        let bufmgr = setup(10);
+        let btree = BTree::create(&bufmgr).unwrap();
+        let large_key = |i: u64| {
+            let mut key = i.to_be_bytes().to_vec();
+            key.resize(1002, 0);
+            key
         };
-        if let Some(next_page_id) = next_page_id {
-            self.buffer = bufmgr.fetch_page(next_page_id)?;
-            self.slot_id = 0;
+        for i in 0u64..500 {
+            btree.insert(&bufmgr, &large_key(i), b"value").unwrap();
+        }
+        for i in 0u64..500 {
+            btree.delete(&bufmgr, &large_key(i)).unwrap();
+        }
+        assert!(collect(&btree, &bufmgr).is_empty());
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let bufmgr = setup(10);
+        let value = vec![0xcc; 100];
+        let btree =
+            BTree::bulk_load(&bufmgr, (0u64..2000).map(|i| (key(i), value.clone())), 90).unwrap();
+        let keys: Vec<_> = collect(&btree, &bufmgr)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!((0u64..2000).map(key).collect::<Vec<_>>(), keys);
+
+        let mut iter = btree.search(&bufmgr, SearchMode::Key(key(1234))).unwrap();
+        assert_eq!(key(1234), iter.next(&bufmgr).unwrap().unwrap().0);
+
+        btree.insert(&bufmgr, &key(5000), &value).unwrap();
+        for i in 0u64..1000 {
+            btree.delete(&bufmgr, &key(i)).unwrap();
+        }
+        let keys: Vec<_> = collect(&btree, &bufmgr)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let expected: Vec<_> = (1000u64..2000).chain(Some(5000)).map(key).collect();
+        assert_eq!(expected, keys);
+
+        assert!(matches!(
+            BTree::bulk_load(&bufmgr, vec![(b"b", b""), (b"a", b"")], 90),
+            Err(Error::UnsortedKeys)
+        ));
+    }
+
+    // Walks the leaf chain from the leftmost leaf, checking the back links,
+    // and returns the keys of each leaf.
+    fn leaf_chain(btree: &BTree, bufmgr: &BufferPoolManager) -> Vec<Vec<Vec<u8>>> {
+        let mut chain = vec![];
+        let mut prev_page_id = None;
+        let mut next_page_id = btree
+            .read_leaf(bufmgr, &Anchor::First, |buffer, _| Ok(Some(buffer.page_id)))
+            .unwrap();
+        while let Some(page_id) = next_page_id {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            let page = buffer.page.read().unwrap();
+            let leaf = leaf::Leaf::new(node::Node::new(&page[..]).body);
+            assert_eq!(prev_page_id, leaf.prev_page_id());
+            chain.push((0..leaf.num_pairs()).map(|i| leaf.key_at(i)).collect());
+            prev_page_id = Some(page_id);
+            next_page_id = leaf.next_page_id();
+        }
+        chain
+    }
+
+    fn count_leaves(btree: &BTree, bufmgr: &BufferPoolManager) -> usize {
+        leaf_chain(btree, bufmgr).len()
+    }
+
+    #[test]
+    fn test_fill_factor() {
+        let bufmgr = setup(10);
+        let value = vec![0xdd; 100];
+        let half = BTree::create_with_fill_factor(&bufmgr, 50).unwrap();
+        let dense = BTree::create(&bufmgr).unwrap();
+        assert_eq!(DEFAULT_FILL_FACTOR, dense.fill_factor(&bufmgr).unwrap());
+        for i in 0u64..1000 {
+            half.insert(&bufmgr, &key(i), &value).unwrap();
+            dense.insert(&bufmgr, &key(i), &value).unwrap();
+        }
+        let half_leaves = count_leaves(&half, &bufmgr);
+        let dense_leaves = count_leaves(&dense, &bufmgr);
+        assert!(dense_leaves * 10 < half_leaves * 6);
+
+        let keys: Vec<_> = collect(&dense, &bufmgr)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!((0u64..1000).map(key).collect::<Vec<_>>(), keys);
+    }
+
+    #[test]
+    fn test_shortest_separator() {
+        assert_eq!(b"b".to_vec(), shortest_separator(b"apple", b"banana"));
+        assert_eq!(
+            b"user:1".to_vec(),
+            shortest_separator(b"user:0999", b"user:1000")
+        );
+        assert_eq!(b"abc".to_vec(), shortest_separator(b"ab", b"abcd"));
+    }
+
+    #[test]
+    fn test_concurrent_insert() {
+        const NUM_THREADS: u64 = 4;
+        const NUM_KEYS: u64 = 600;
+        let bufmgr = Arc::new(setup(100));
+        let meta_page_id = BTree::create(&bufmgr).unwrap().meta_page_id;
+        let value = vec![0xee; 100];
+
+        let writers: Vec<_> = (0..NUM_THREADS)
+            .map(|t| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let value = value.clone();
+                std::thread::spawn(move || {
+                    let btree = BTree::new(meta_page_id);
+                    for i in 0..NUM_KEYS / NUM_THREADS {
+                        let k = key((i * 7919 % (NUM_KEYS / NUM_THREADS)) * NUM_THREADS + t);
+                        btree.insert(&bufmgr, &k, &value).unwrap();
+                        assert_eq!(Some(value.clone()), btree.get(&bufmgr, &k).unwrap());
+                    }
+                })
+            })
+            .collect();
+        let reader = {
+            let bufmgr = Arc::clone(&bufmgr);
+            std::thread::spawn(move || {
+                let btree = BTree::new(meta_page_id);
+                for _ in 0..20 {
+                    let keys: Vec<_> = collect(&btree, &bufmgr)
+                        .into_iter()
+                        .map(|(key, _)| key)
+                        .collect();
+                    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+                }
+            })
+        };
+        for handle in writers.into_iter().chain(Some(reader)) {
+            handle.join().unwrap();
+        }
+
+        let btree = BTree::new(meta_page_id);
+        let chain = leaf_chain(&btree, &bufmgr);
+        assert!(chain.iter().all(|keys| !keys.is_empty()));
+        let keys: Vec<_> = chain.into_iter().flatten().collect();
+        assert_eq!((0..NUM_KEYS).map(key).collect::<Vec<_>>(), keys);
+        let mut iter = btree
+            .search_with_direction(&bufmgr, SearchMode::End, Direction::Backward)
+            .unwrap();
+        let mut rev_keys = vec![];
+        while let Some((key, _)) = iter.prev(&bufmgr).unwrap() {
+            rev_keys.push(key);
         }
-        Ok(value)
+        rev_keys.reverse();
+        assert_eq!(keys, rev_keys);
     }
 }
@@ -32,7 +32,7 @@ impl<B: ByteSlice> Branch<B> {
 
     pub fn search_slot_id(&self, key: &[u8]) -> Result<usize, usize> {
         binary_search_by(self.num_pairs(), |slot_id| {
-            self.pair_at(slot_id).key.cmp(&key)
+            self.pair_at(slot_id).key.cmp(key)
         })
     }
 
@@ -56,13 +56,30 @@ impl<B: ByteSlice> Branch<B> {
         }
     }
 
-    pub fn pair_at(&self, slot_id: usize) -> Pair {
+    pub fn pair_at(&self, slot_id: usize) -> Pair<'_> {
         Pair::from_bytes(&self.body[slot_id])
     }
 
     pub fn max_pair_size(&self) -> usize {
         self.body.capacity() / 2 - size_of::<slotted::Pointer>()
     }
+
+    pub fn is_half_full(&self) -> bool {
+        self.is_filled(50)
+    }
+
+    pub fn is_filled(&self, fill_factor: u8) -> bool {
+        self.body.free_space() * 100 < self.body.capacity() * (100 - fill_factor as usize)
+    }
+
+    pub fn can_merge(&self, other: &Branch<impl ByteSlice>, sep_key: &[u8]) -> bool {
+        let sep_pair = Pair {
+            key: sep_key,
+            value: self.header.right_child.as_bytes(),
+        };
+        let sep_pair_size = sep_pair.to_bytes().len() + size_of::<slotted::Pointer>();
+        other.body.capacity() - other.body.free_space() + sep_pair_size <= self.body.free_space()
+    }
 }
 
 impl<B: ByteSliceMut> Branch<B> {
@@ -73,6 +90,27 @@ impl<B: ByteSliceMut> Branch<B> {
         self.header.right_child = right_child;
     }
 
+    pub fn initialize_with_child(&mut self, child: PageId) {
+        self.body.initialize();
+        self.header.right_child = child;
+    }
+
+    #[must_use = "insertion may fail"]
+    pub fn push_child(&mut self, key: &[u8], child: PageId, fill_factor: u8) -> Option<()> {
+        let pair = Pair {
+            key,
+            value: child.as_bytes(),
+        };
+        let pair_size = pair.to_bytes().len() + size_of::<slotted::Pointer>();
+        let used = self.body.capacity() - self.body.free_space() + pair_size;
+        if self.num_pairs() >= 2 && used * 100 > self.body.capacity() * fill_factor as usize {
+            return None;
+        }
+        self.insert(self.num_pairs(), key, self.header.right_child)?;
+        self.header.right_child = child;
+        Some(())
+    }
+
     pub fn fill_right_child(&mut self) -> Vec<u8> {
         let last_id = self.num_pairs() - 1;
         let Pair { key, value } = self.pair_at(last_id);
@@ -96,8 +134,36 @@ impl<B: ByteSliceMut> Branch<B> {
         Some(())
     }
 
-    fn is_half_full(&self) -> bool {
-        2 * self.body.free_space() < self.body.capacity()
+    pub fn set_child_at(&mut self, child_idx: usize, page_id: PageId) {
+        if child_idx == self.num_pairs() {
+            self.header.right_child = page_id;
+        } else {
+            let key = self.pair_at(child_idx).key.to_vec();
+            let pair = Pair {
+                key: &key,
+                value: page_id.as_bytes(),
+            };
+            self.body[child_idx].copy_from_slice(&pair.to_bytes());
+        }
+    }
+
+    #[must_use = "update may fail"]
+    pub fn set_key_at(&mut self, slot_id: usize, key: &[u8]) -> Option<()> {
+        let child = self.child_at(slot_id);
+        let pair = Pair {
+            key,
+            value: child.as_bytes(),
+        };
+        let pair_bytes = pair.to_bytes();
+        self.body.resize(slot_id, pair_bytes.len())?;
+        self.body[slot_id].copy_from_slice(&pair_bytes);
+        Some(())
+    }
+
+    pub fn remove(&mut self, slot_id: usize) {
+        let left_child = self.child_at(slot_id);
+        self.set_child_at(slot_id + 1, left_child);
+        self.body.remove(slot_id);
     }
 
     pub fn split_insert(
@@ -105,10 +171,11 @@ impl<B: ByteSliceMut> Branch<B> {
         new_branch: &mut Branch<impl ByteSliceMut>,
         new_key: &[u8],
         new_page_id: PageId,
+        fill_factor: u8,
     ) -> Vec<u8> {
         new_branch.body.initialize();
         loop {
-            if new_branch.is_half_full() {
+            if new_branch.is_filled(fill_factor) || self.num_pairs() == 1 {
                 let index = self
                     .search_slot_id(new_key)
                     .expect_err("key must be unique");
@@ -122,7 +189,7 @@ impl<B: ByteSliceMut> Branch<B> {
                 new_branch
                     .insert(new_branch.num_pairs(), new_key, new_page_id)
                     .expect("new branch must have space");
-                while !new_branch.is_half_full() {
+                while !new_branch.is_filled(fill_factor) && self.num_pairs() > 1 {
                     self.transfer(new_branch);
                 }
                 break;
@@ -138,4 +205,49 @@ impl<B: ByteSliceMut> Branch<B> {
         dest.body[next_index].copy_from_slice(&self.body[0]);
         self.body.remove(0);
     }
+
+    pub fn merge(&mut self, right: &mut Branch<impl ByteSliceMut>, sep_key: &[u8]) {
+        self.insert(self.num_pairs(), sep_key, self.header.right_child)
+            .expect("left branch must have space");
+        while right.num_pairs() > 0 {
+            right.transfer(self);
+        }
+        self.header.right_child = right.header.right_child;
+    }
+
+    pub fn borrow_from_right(
+        &mut self,
+        right: &mut Branch<impl ByteSliceMut>,
+        sep_key: &[u8],
+    ) -> Vec<u8> {
+        let mut sep_key = sep_key.to_vec();
+        while !self.is_half_full() && right.num_pairs() > 0 {
+            self.insert(self.num_pairs(), &sep_key, self.header.right_child)
+                .expect("left branch must have space");
+            let Pair { key, value } = right.pair_at(0);
+            sep_key = key.to_vec();
+            self.header.right_child = value.into();
+            right.body.remove(0);
+        }
+        sep_key
+    }
+
+    pub fn lend_to_right(
+        &mut self,
+        right: &mut Branch<impl ByteSliceMut>,
+        sep_key: &[u8],
+    ) -> Vec<u8> {
+        let mut sep_key = sep_key.to_vec();
+        while !right.is_half_full() && self.num_pairs() > 0 {
+            right
+                .insert(0, &sep_key, self.header.right_child)
+                .expect("right branch must have space");
+            let last_id = self.num_pairs() - 1;
+            let Pair { key, value } = self.pair_at(last_id);
+            sep_key = key.to_vec();
+            self.header.right_child = value.into();
+            self.body.remove(last_id);
+        }
+        sep_key
+    }
 }
@@ -1,8 +1,9 @@
+use std::cmp::Ordering;
 use std::mem::size_of;
 
 use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 
-use super::Pair;
+use super::{shortest_separator, Pair};
 use crate::bsearch::binary_search_by;
 use crate::disk::PageId;
 use crate::slotted::{self, Slotted};
@@ -19,6 +20,12 @@ pub struct Leaf<B> {
     body: Slotted<B>,
 }
 
+const PREFIX_SLOT_ID: usize = 0;
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
 impl<B: ByteSlice> Leaf<B> {
     pub fn new(bytes: B) -> Self {
         let (header, body) =
@@ -36,28 +43,109 @@ impl<B: ByteSlice> Leaf<B> {
     }
 
     pub fn num_pairs(&self) -> usize {
-        self.body.num_slots()
+        self.body.num_slots() - 1
+    }
+
+    pub fn prefix(&self) -> &[u8] {
+        &self.body[PREFIX_SLOT_ID]
+    }
+
+    fn cmp_key_at(&self, slot_id: usize, key: &[u8]) -> Ordering {
+        let prefix = self.prefix();
+        if key.len() < prefix.len() {
+            return prefix.cmp(key);
+        }
+        let (key_prefix, key_suffix) = key.split_at(prefix.len());
+        prefix
+            .cmp(key_prefix)
+            .then_with(|| self.stored_pair_at(slot_id).key.cmp(key_suffix))
     }
 
     pub fn search_slot_id(&self, key: &[u8]) -> Result<usize, usize> {
-        binary_search_by(self.num_pairs(), |slot_id| {
-            self.pair_at(slot_id).key.cmp(&key)
-        })
+        binary_search_by(self.num_pairs(), |slot_id| self.cmp_key_at(slot_id, key))
     }
 
     #[cfg(test)]
-    pub fn search_pair(&self, key: &[u8]) -> Option<Pair> {
+    pub fn search_value(&self, key: &[u8]) -> Option<&[u8]> {
         let slot_id = self.search_slot_id(key).ok()?;
-        Some(self.pair_at(slot_id))
+        Some(self.value_at(slot_id))
     }
 
-    pub fn pair_at(&self, slot_id: usize) -> Pair {
-        Pair::from_bytes(&self.body[slot_id])
+    fn stored_pair_at(&self, slot_id: usize) -> Pair<'_> {
+        Pair::from_bytes(&self.body[slot_id + 1])
+    }
+
+    pub fn key_at(&self, slot_id: usize) -> Vec<u8> {
+        let mut key = self.prefix().to_vec();
+        key.extend_from_slice(self.stored_pair_at(slot_id).key);
+        key
+    }
+
+    pub fn value_at(&self, slot_id: usize) -> &[u8] {
+        self.stored_pair_at(slot_id).value
+    }
+
+    fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..self.num_pairs())
+            .map(|slot_id| (self.key_at(slot_id), self.value_at(slot_id).to_vec()))
+            .collect()
     }
 
     pub fn max_pair_size(&self) -> usize {
-        self.body.capacity() / 2 - size_of::<slotted::Pointer>()
+        (self.body.capacity() - size_of::<slotted::Pointer>()) / 2 - size_of::<slotted::Pointer>()
+    }
+
+    pub fn is_half_full(&self) -> bool {
+        self.is_filled(50)
     }
+
+    pub fn is_filled(&self, fill_factor: u8) -> bool {
+        self.body.free_space() * 100 < self.body.capacity() * (100 - fill_factor as usize)
+    }
+}
+
+fn encoded_size(pairs: &[(Vec<u8>, Vec<u8>)]) -> usize {
+    let prefix_len = match (pairs.first(), pairs.last()) {
+        (Some((first, _)), Some((last, _))) => common_prefix_len(first, last),
+        _ => 0,
+    };
+    let pairs_size: usize = pairs
+        .iter()
+        .map(|(key, value)| {
+            let pair = Pair {
+                key: &key[prefix_len..],
+                value,
+            };
+            pair.to_bytes().len() + size_of::<slotted::Pointer>()
+        })
+        .sum();
+    prefix_len + size_of::<slotted::Pointer>() + pairs_size
+}
+
+// The first split point in 1..len for which `is_past` holds, or len if there
+// is none; `is_past` may only turn from false to true as the point moves
+// right.
+fn first_split(len: usize, is_past: impl Fn(usize) -> bool) -> usize {
+    let found = binary_search_by(len - 1, |idx| {
+        if is_past(idx + 1) {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        }
+    });
+    found.unwrap_err() + 1
+}
+
+// The split point closest to `target` at which both halves fit into a leaf of
+// `capacity` bytes. The encoded size of the left half only grows with the
+// split point, and that of the right half only shrinks.
+fn fitting_split(pairs: &[(Vec<u8>, Vec<u8>)], target: usize, capacity: usize) -> Option<usize> {
+    let lowest = first_split(pairs.len(), |mid| encoded_size(&pairs[mid..]) <= capacity);
+    let highest = first_split(pairs.len(), |mid| encoded_size(&pairs[..mid]) > capacity) - 1;
+    if lowest > highest {
+        return None;
+    }
+    Some(target.max(lowest).min(highest))
 }
 
 impl<B: ByteSliceMut> Leaf<B> {
@@ -65,6 +153,9 @@ impl<B: ByteSliceMut> Leaf<B> {
         self.header.prev_page_id = PageId::INVALID_PAGE_ID;
         self.header.next_page_id = PageId::INVALID_PAGE_ID;
         self.body.initialize();
+        self.body
+            .insert(PREFIX_SLOT_ID, 0)
+            .expect("new leaf must have space for prefix");
     }
 
     pub fn set_prev_page_id(&mut self, prev_page_id: Option<PageId>) {
@@ -75,55 +166,208 @@ impl<B: ByteSliceMut> Leaf<B> {
         self.header.next_page_id = next_page_id.into()
     }
 
+    #[must_use = "rebuild may fail"]
+    fn rebuild(&mut self, pairs: &[(Vec<u8>, Vec<u8>)]) -> Option<()> {
+        if encoded_size(pairs) > self.body.capacity() {
+            return None;
+        }
+        let prefix_len = match (pairs.first(), pairs.last()) {
+            (Some((first, _)), Some((last, _))) => common_prefix_len(first, last),
+            _ => 0,
+        };
+        self.body.initialize();
+        self.body
+            .insert(PREFIX_SLOT_ID, prefix_len)
+            .expect("leaf must have space for prefix");
+        if let Some((first, _)) = pairs.first() {
+            self.body[PREFIX_SLOT_ID].copy_from_slice(&first[..prefix_len]);
+        }
+        for (slot_id, (key, value)) in pairs.iter().enumerate() {
+            self.insert_stored(slot_id, &key[prefix_len..], value)
+                .expect("leaf must have space for rebuilt pairs");
+        }
+        Some(())
+    }
+
+    pub fn compact(&mut self) {
+        let pairs = self.pairs();
+        self.rebuild(&pairs)
+            .expect("compaction must not need extra space");
+    }
+
+    #[must_use = "insertion may fail"]
+    fn insert_stored(&mut self, slot_id: usize, suffix: &[u8], value: &[u8]) -> Option<()> {
+        let pair = Pair { key: suffix, value };
+        let pair_bytes = pair.to_bytes();
+        self.body.insert(slot_id + 1, pair_bytes.len())?;
+        self.body[slot_id + 1].copy_from_slice(&pair_bytes);
+        Some(())
+    }
+
     #[must_use = "insertion may fail"]
     pub fn insert(&mut self, slot_id: usize, key: &[u8], value: &[u8]) -> Option<()> {
-        let pair = Pair { key, value };
+        assert!(Pair { key, value }.to_bytes().len() <= self.max_pair_size());
+        let prefix_len = self.prefix().len();
+        if key.starts_with(self.prefix()) {
+            return self.insert_stored(slot_id, &key[prefix_len..], value);
+        }
+        let mut pairs = self.pairs();
+        pairs.insert(slot_id, (key.to_vec(), value.to_vec()));
+        self.rebuild(&pairs)
+    }
+
+    #[must_use = "update may fail"]
+    pub fn update(&mut self, slot_id: usize, value: &[u8]) -> Option<()> {
+        let key = self.key_at(slot_id);
+        assert!(Pair { key: &key, value }.to_bytes().len() <= self.max_pair_size());
+        let pair = Pair {
+            key: &key[self.prefix().len()..],
+            value,
+        };
         let pair_bytes = pair.to_bytes();
-        assert!(pair_bytes.len() <= self.max_pair_size());
-        self.body.insert(slot_id, pair_bytes.len())?;
-        self.body[slot_id].copy_from_slice(&pair_bytes);
+        self.body.resize(slot_id + 1, pair_bytes.len())?;
+        self.body[slot_id + 1].copy_from_slice(&pair_bytes);
         Some(())
     }
 
-    fn is_half_full(&self) -> bool {
-        2 * self.body.free_space() < self.body.capacity()
+    #[must_use = "insertion may fail"]
+    pub fn append(&mut self, key: &[u8], value: &[u8], fill_factor: u8) -> Option<()> {
+        let pair_size = Pair { key, value }.to_bytes().len() + size_of::<slotted::Pointer>();
+        let used = self.body.capacity() - self.body.free_space() + pair_size;
+        if self.num_pairs() > 0 && used * 100 > self.body.capacity() * fill_factor as usize {
+            return None;
+        }
+        self.insert(self.num_pairs(), key, value)
+    }
+
+    pub fn remove(&mut self, slot_id: usize) {
+        self.body.remove(slot_id + 1);
     }
 
+    // Moves the smaller keys into the empty `new_leaf` until it is filled to
+    // `fill_factor`, and returns the separator between the two leaves.
     pub fn split_insert(
         &mut self,
         new_leaf: &mut Leaf<impl ByteSliceMut>,
         new_key: &[u8],
         new_value: &[u8],
+        fill_factor: u8,
     ) -> Vec<u8> {
+        let slot_id = self
+            .search_slot_id(new_key)
+            .expect_err("key must be unique");
+        let mut pairs = self.pairs();
+        pairs.insert(slot_id, (new_key.to_vec(), new_value.to_vec()));
+        let capacity = self.body.capacity();
+        let target = first_split(pairs.len(), |mid| {
+            encoded_size(&pairs[..mid]) * 100 > capacity * fill_factor as usize
+        });
+        // a full leaf and one more pair of at most `max_pair_size` always fit
+        // into two leaves
+        let mid = fitting_split(&pairs, target, capacity).expect("pairs must fit into two leaves");
         new_leaf.initialize();
-        loop {
-            if new_leaf.is_half_full() {
-                let index = self
-                    .search_slot_id(new_key)
-                    .expect_err("key must be unique");
-                self.insert(index, new_key, new_value)
-                    .expect("old leaf must have space");
-                break;
-            }
-            if self.pair_at(0).key < new_key {
-                self.transfer(new_leaf);
-            } else {
-                new_leaf
-                    .insert(new_leaf.num_pairs(), new_key, new_value)
-                    .expect("new leaf must have space");
-                while !new_leaf.is_half_full() {
-                    self.transfer(new_leaf);
-                }
-                break;
-            }
+        new_leaf
+            .rebuild(&pairs[..mid])
+            .expect("new leaf must have space");
+        self.rebuild(&pairs[mid..])
+            .expect("old leaf must have space");
+        shortest_separator(&new_leaf.key_at(new_leaf.num_pairs() - 1), &self.key_at(0))
+    }
+
+    // Moves all pairs of `right` into this leaf, or leaves it untouched if
+    // they do not fit.
+    #[must_use = "merge may fail"]
+    pub fn merge(&mut self, right: &Leaf<impl ByteSlice>) -> Option<()> {
+        let mut pairs = self.pairs();
+        pairs.extend(right.pairs());
+        self.rebuild(&pairs)
+    }
+
+    // Evens out the pairs of this leaf and its right sibling. Both are rebuilt
+    // at once, since moving pairs one at a time changes their prefixes and
+    // can overflow a leaf on the way.
+    #[must_use = "redistribution may fail"]
+    pub fn redistribute(&mut self, right: &mut Leaf<impl ByteSliceMut>) -> Option<()> {
+        let mut pairs = self.pairs();
+        pairs.extend(right.pairs());
+        if pairs.len() < 2 {
+            return None;
         }
-        self.pair_at(0).key.to_vec()
+        let target = first_split(pairs.len(), |mid| {
+            encoded_size(&pairs[..mid]) >= encoded_size(&pairs[mid..])
+        });
+        let mid = fitting_split(&pairs, target, self.body.capacity())?;
+        self.rebuild(&pairs[..mid])?;
+        right.rebuild(&pairs[mid..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_remove() {
+        let mut page_data = vec![0; 100];
+        let mut leaf_page = Leaf::new(page_data.as_mut_slice());
+        leaf_page.initialize();
+
+        let id = leaf_page.search_slot_id(b"deadbeef").unwrap_err();
+        assert!(leaf_page.insert(id, b"deadbeef", b"world").is_some());
+        let id = leaf_page.search_slot_id(b"facebook").unwrap_err();
+        assert!(leaf_page.insert(id, b"facebook", b"!").is_some());
+        assert_eq!(b"world", leaf_page.search_value(b"deadbeef").unwrap());
+
+        let id = leaf_page.search_slot_id(b"deadbeef").unwrap();
+        leaf_page.remove(id);
+        assert!(leaf_page.search_value(b"deadbeef").is_none());
+        assert_eq!(b"!", leaf_page.search_value(b"facebook").unwrap());
+        assert_eq!(1, leaf_page.num_pairs());
+    }
+
+    #[test]
+    fn test_redistribute_and_merge() {
+        let mut page_data = vec![0; 100];
+        let mut leaf_page = Leaf::new(page_data.as_mut_slice());
+        leaf_page.initialize();
+        assert!(leaf_page.insert(0, b"deadbeef", b"world").is_some());
+        assert!(leaf_page.insert(1, b"facebook", b"!").is_some());
+        assert!(leaf_page.insert(2, b"feedface", b"?").is_some());
+
+        let mut dest_data = vec![0; 100];
+        let mut dest_page = Leaf::new(dest_data.as_mut_slice());
+        dest_page.initialize();
+        assert!(dest_page.insert(0, b"hello", b"world").is_some());
+
+        assert!(leaf_page.redistribute(&mut dest_page).is_some());
+        assert_eq!(2, leaf_page.num_pairs());
+        assert_eq!(2, dest_page.num_pairs());
+        assert_eq!(b"feedface", dest_page.key_at(0).as_slice());
+        assert_eq!(b"hello", dest_page.key_at(1).as_slice());
+
+        assert!(leaf_page.merge(&dest_page).is_some());
+        assert_eq!(4, leaf_page.num_pairs());
+        assert_eq!(b"hello", leaf_page.key_at(3).as_slice());
+        assert_eq!(Some(&b"?"[..]), leaf_page.search_value(b"feedface"));
     }
 
-    pub fn transfer(&mut self, dest: &mut Leaf<impl ByteSliceMut>) {
-        let next_index = dest.num_pairs();
-        assert!(dest.body.insert(next_index, self.body[0].len()).is_some());
-        dest.body[next_index].copy_from_slice(&self.body[0]);
-        self.body.remove(0);
+    #[test]
+    fn test_prefix_compression() {
+        let mut page_data = vec![0; 200];
+        let mut leaf_page = Leaf::new(page_data.as_mut_slice());
+        leaf_page.initialize();
+        assert!(leaf_page.insert(0, b"user:0001", b"a").is_some());
+        assert!(leaf_page.insert(1, b"user:0002", b"b").is_some());
+        leaf_page.compact();
+        assert_eq!(b"user:000", leaf_page.prefix());
+        assert_eq!(b"a", leaf_page.search_value(b"user:0001").unwrap());
+        assert_eq!(Err(2), leaf_page.search_slot_id(b"user:1"));
+        assert_eq!(Err(0), leaf_page.search_slot_id(b"user"));
+
+        let id = leaf_page.search_slot_id(b"user:0010").unwrap_err();
+        assert!(leaf_page.insert(id, b"user:0010", b"c").is_some());
+        assert_eq!(b"user:00", leaf_page.prefix());
+        assert_eq!(b"user:0010", leaf_page.key_at(2).as_slice());
+        assert_eq!(b"b", leaf_page.search_value(b"user:0002").unwrap());
     }
 }
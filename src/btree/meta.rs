@@ -6,6 +6,8 @@ use crate::disk::PageId;
 #[repr(C)]
 pub struct Header {
     pub root_page_id: PageId,
+    pub fill_factor: u8,
+    _pad: [u8; 7],
 }
 
 pub struct Meta<B> {
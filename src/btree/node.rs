@@ -2,6 +2,7 @@ use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 
 use super::branch::Branch;
 use super::leaf::Leaf;
+use crate::disk::CHECKSUM_SIZE;
 
 pub const NODE_TYPE_LEAF: [u8; 8] = *b"LEAF    ";
 pub const NODE_TYPE_BRANCH: [u8; 8] = *b"BRANCH  ";
@@ -19,6 +20,8 @@ pub struct Node<B> {
 
 impl<B: ByteSlice> Node<B> {
     pub fn new(bytes: B) -> Self {
+        let body_len = bytes.len() - CHECKSUM_SIZE;
+        let (bytes, _checksum) = bytes.split_at(body_len);
         let (header, body) = LayoutVerified::new_from_prefix(bytes).expect("node must be aligned");
         Self { header, body }
     }
@@ -0,0 +1,51 @@
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
+
+use crate::disk::{PageId, CHECKSUM_SIZE, PAGE_SIZE};
+
+pub const MAX_DATA_SIZE: usize = PAGE_SIZE - CHECKSUM_SIZE - size_of::<Header>();
+
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    next_page_id: PageId,
+    len: u64,
+}
+
+pub struct Overflow<B> {
+    header: LayoutVerified<B, Header>,
+    body: B,
+}
+
+impl<B: ByteSlice> Overflow<B> {
+    pub fn new(bytes: B) -> Self {
+        let (header, body) =
+            LayoutVerified::new_from_prefix(bytes).expect("overflow header must be aligned");
+        Self { header, body }
+    }
+
+    pub fn next_page_id(&self) -> Option<PageId> {
+        self.header.next_page_id.valid()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.body[..self.header.len as usize]
+    }
+}
+
+impl<B: ByteSliceMut> Overflow<B> {
+    pub fn initialize(&mut self) {
+        self.header.next_page_id = PageId::INVALID_PAGE_ID;
+        self.header.len = 0;
+    }
+
+    pub fn set_next_page_id(&mut self, next_page_id: Option<PageId>) {
+        self.header.next_page_id = next_page_id.into()
+    }
+
+    pub fn set_data(&mut self, data: &[u8]) {
+        self.body[..data.len()].copy_from_slice(data);
+        self.header.len = data.len() as u64;
+    }
+}
@@ -1,184 +1,1213 @@
-use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::fmt;
 use std::io;
-use std::ops::{Index, IndexMut};
-use std::rc::Rc;
+use std::mem;
+use std::ops::{Deref, DerefMut, Index};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use crate::disk::{DiskManager, PageId, PAGE_SIZE};
+use crate::disk::{self, DiskManager, DiskStats, PageId, PAGE_SIZE};
+use crate::wal::Lsn;
+
+mod policy;
+
+pub use policy::{Policy, ReplacementPolicy};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
-    #[error("no free buffer available in buffer pool")]
-    NoFreeBuffer,
+    #[error("no free buffer available in buffer pool ({0})")]
+    NoFreeBuffer(PinReport),
+    #[error("checksum mismatch in page {page_id:?}")]
+    Corruption { page_id: PageId },
+    #[error("page {page_id:?} is latched for writing")]
+    PageBusy { page_id: PageId },
+}
+
+// Only opening a file reports anything but I/O errors and corruption.
+impl From<disk::Error> for Error {
+    fn from(err: disk::Error) -> Self {
+        match err {
+            disk::Error::Io(err) => Error::Io(err),
+            disk::Error::Corruption { page_id } => Error::Corruption { page_id },
+            err => Error::Io(io::Error::new(io::ErrorKind::InvalidData, err)),
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct BufferId(usize);
 
-pub type Page = [u8; PAGE_SIZE];
+// Pages are kept 8-byte aligned so that their headers can be read in place,
+// which a byte array inside a lock would not guarantee.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(8))]
+pub struct Page([u8; PAGE_SIZE]);
+
+impl Default for Page {
+    fn default() -> Self {
+        Self([0u8; PAGE_SIZE])
+    }
+}
+
+impl Deref for Page {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
 
+impl DerefMut for Page {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+// The page lock is the frame's latch: holders of a `PageGuard` take it in
+// shared mode to read the page and in exclusive mode to modify it.
 #[derive(Debug)]
 pub struct Buffer {
     pub page_id: PageId,
-    pub page: RefCell<Page>,
-    pub is_dirty: Cell<bool>,
+    pub page: RwLock<Page>,
+    is_dirty: AtomicBool,
+    pin_count: AtomicUsize,
+}
+
+impl Buffer {
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty.load(Ordering::Acquire)
+    }
 }
 
 impl Default for Buffer {
     fn default() -> Self {
         Self {
             page_id: Default::default(),
-            page: RefCell::new([0u8; PAGE_SIZE]),
-            is_dirty: Cell::new(false),
+            page: RwLock::new(Page::default()),
+            is_dirty: AtomicBool::new(false),
+            pin_count: AtomicUsize::new(0),
         }
     }
 }
 
+// Pins a page in the pool until it is dropped. Cloning the guard pins the
+// page once more.
+pub struct PageGuard {
+    buffer: Arc<Buffer>,
+}
+
+impl PageGuard {
+    // The caller must hold the lock of the frame owning `buffer`, so that the
+    // frame cannot be chosen as a victim in the meantime.
+    fn pin(buffer: &Arc<Buffer>) -> Self {
+        buffer.pin_count.fetch_add(1, Ordering::AcqRel);
+        Self {
+            buffer: Arc::clone(buffer),
+        }
+    }
+
+    pub fn pin_count(&self) -> usize {
+        self.buffer.pin_count.load(Ordering::Acquire)
+    }
+}
+
+impl Clone for PageGuard {
+    fn clone(&self) -> Self {
+        Self::pin(&self.buffer)
+    }
+}
+
+impl Deref for PageGuard {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl Drop for PageGuard {
+    fn drop(&mut self) {
+        self.buffer.pin_count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl fmt::Debug for PageGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageGuard")
+            .field("page_id", &self.buffer.page_id)
+            .field("pin_count", &self.pin_count())
+            .finish()
+    }
+}
+
+// What keeps the pool from reusing frames: pages pinned by guards, and
+// pages with unlogged changes, which stay until the next log point.
+#[derive(Debug, Clone, Default)]
+pub struct PinReport {
+    pub pinned: Vec<(PageId, usize)>,
+    pub unlogged: usize,
+}
+
+impl fmt::Display for PinReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pinned pages:")?;
+        if self.pinned.is_empty() {
+            write!(f, " none")?;
+        }
+        for (page_id, pin_count) in &self.pinned {
+            write!(f, " {}x{}", page_id.to_u64(), pin_count)?;
+        }
+        write!(f, ", unlogged pages: {}", self.unlogged)
+    }
+}
+
+// A frame is pinned while any `PageGuard` handed out for it is alive. The
+// pool itself briefly holds extra references to copy page images, and those
+// keep the frame from being reused as well.
 #[derive(Debug, Default)]
 pub struct Frame {
-    usage_count: u64,
-    buffer: Rc<Buffer>,
+    // lsn of the group holding the logged image that the heap file lacks
+    pending_write: Option<Lsn>,
+    buffer: Arc<Buffer>,
 }
 
 pub struct BufferPool {
-    buffers: Vec<Frame>,
-    next_victim_id: BufferId,
+    buffers: Vec<Mutex<Frame>>,
+    policy: Mutex<Box<dyn ReplacementPolicy>>,
 }
 
 impl BufferPool {
     pub fn new(pool_size: usize) -> Self {
+        Self::with_policy(pool_size, Policy::default())
+    }
+
+    pub fn with_policy(pool_size: usize, policy: Policy) -> Self {
         let mut buffers = vec![];
         buffers.resize_with(pool_size, Default::default);
-        let next_victim_id = BufferId::default();
         Self {
             buffers,
-            next_victim_id,
+            policy: Mutex::new(policy.build(pool_size)),
         }
     }
 
-    fn size(&self) -> usize {
-        self.buffers.len()
+    // Returns the victim with its frame still locked, so that nobody can pin
+    // it before the caller has reused it. The policy lock is taken before
+    // frame locks here, so the policy is only told about accesses once the
+    // frame lock is released.
+    fn evict(&self) -> Option<(BufferId, MutexGuard<'_, Frame>)> {
+        let mut policy = self.policy.lock().unwrap();
+        // unlogged changes may belong to an unfinished operation, so those
+        // pages stay in the pool until they are logged
+        let is_evictable = |frame: &mut Frame| {
+            !frame.buffer.is_dirty.load(Ordering::Acquire)
+                && frame.buffer.pin_count.load(Ordering::Acquire) == 0
+                && Arc::get_mut(&mut frame.buffer).is_some()
+        };
+        loop {
+            let victim_id =
+                policy.victim(&|buffer_id| is_evictable(&mut self[buffer_id].lock().unwrap()))?;
+            let mut frame = self[victim_id].lock().unwrap();
+            // the victim may have been pinned since the policy looked at it
+            if is_evictable(&mut frame) {
+                return Some((victim_id, frame));
+            }
+        }
     }
 
-    fn evict(&mut self) -> Option<BufferId> {
-        let pool_size = self.size();
-        let mut consecutive_pinned = 0;
-
-        let victim_id = loop {
-            let next_victim_id = self.next_victim_id;
-            let frame = &mut self[next_victim_id];
-            if frame.usage_count == 0 {
-                break self.next_victim_id;
-            }
+    fn load(&self, buffer_id: BufferId, page_id: PageId) {
+        self.policy.lock().unwrap().load(buffer_id, page_id);
+    }
 
-            if Rc::get_mut(&mut frame.buffer).is_some() {
-                frame.usage_count -= 1;
-                consecutive_pinned = 0;
-            } else {
-                consecutive_pinned += 1;
-                if consecutive_pinned >= pool_size {
-                    return None;
-                }
-            }
-            self.next_victim_id = self.increment_id(self.next_victim_id);
-        };
-        Some(victim_id)
+    fn access(&self, buffer_id: BufferId) {
+        self.policy.lock().unwrap().access(buffer_id);
     }
 
-    fn increment_id(&self, buffer_id: BufferId) -> BufferId {
-        BufferId((buffer_id.0 + 1) % self.size())
+    fn free(&self, buffer_id: BufferId) {
+        self.policy.lock().unwrap().free(buffer_id);
     }
 }
 
 impl Index<BufferId> for BufferPool {
-    type Output = Frame;
+    type Output = Mutex<Frame>;
 
     fn index(&self, index: BufferId) -> &Self::Output {
         &self.buffers[index.0]
     }
 }
 
-impl IndexMut<BufferId> for BufferPool {
-    fn index_mut(&mut self, index: BufferId) -> &mut Self::Output {
-        &mut self.buffers[index.0]
+const NUM_PAGE_TABLE_SHARDS: usize = 16;
+
+// Lookups of different pages rarely contend, since each shard has its own
+// lock and a page id always maps to the same shard.
+struct PageTable {
+    shards: Vec<Mutex<HashMap<PageId, BufferId>>>,
+}
+
+impl PageTable {
+    fn new() -> Self {
+        let mut shards = vec![];
+        shards.resize_with(NUM_PAGE_TABLE_SHARDS, Default::default);
+        Self { shards }
+    }
+
+    fn shard(&self, page_id: PageId) -> &Mutex<HashMap<PageId, BufferId>> {
+        &self.shards[page_id.to_u64() as usize % self.shards.len()]
+    }
+
+    fn get(&self, page_id: PageId) -> Option<BufferId> {
+        self.shard(page_id).lock().unwrap().get(&page_id).copied()
+    }
+
+    fn insert(&self, page_id: PageId, buffer_id: BufferId) {
+        self.shard(page_id)
+            .lock()
+            .unwrap()
+            .insert(page_id, buffer_id);
+    }
+
+    fn remove(&self, page_id: PageId) {
+        self.shard(page_id).lock().unwrap().remove(&page_id);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+    no_free_buffer: AtomicU64,
+}
+
+impl Counters {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// A snapshot of the counters since the pool was created or last reset.
+// Evictions count frames that held a page; write-backs are evictions that
+// had to write the page first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+    pub no_free_buffer: u64,
+    pub disk: DiskStats,
+}
+
+impl BufferStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+impl fmt::Display for BufferStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "buffer pool: {} hits, {} misses ({:.1}% hit ratio), {} evictions, {} write-backs, {} no free buffer",
+            self.hits,
+            self.misses,
+            self.hit_ratio() * 100.0,
+            self.evictions,
+            self.write_backs,
+            self.no_free_buffer,
+        )?;
+        write!(
+            f,
+            "disk: {} page reads, {} page writes ({} vectored), {} logged pages, {} log syncs, {} syncs",
+            self.disk.page_reads,
+            self.disk.page_writes,
+            self.disk.vectored_writes,
+            self.disk.log_pages,
+            self.disk.log_syncs,
+            self.disk.syncs,
+        )
     }
 }
 
+// Cache hits only take a page table shard and the frame lock. Misses and
+// every other change of the frame to page mapping are serialized by the disk
+// lock, which is always taken before any frame lock. Nobody waits for a page
+// latch while holding either of them.
 pub struct BufferPoolManager {
-    disk: DiskManager,
+    disk: Mutex<DiskManager>,
     pool: BufferPool,
-    page_table: HashMap<PageId, BufferId>,
+    page_table: PageTable,
+    pending_free: Mutex<Vec<PageId>>,
+    // frames marked dirty since the last log point, so that a log point does
+    // not have to look at the whole pool; entries may repeat or have been
+    // logged already
+    dirty_frames: Mutex<Vec<BufferId>>,
+    operation_latch: RwLock<()>,
+    counters: Counters,
 }
 
 impl BufferPoolManager {
     pub fn new(disk: DiskManager, pool: BufferPool) -> Self {
-        let page_table = HashMap::new();
         Self {
-            disk,
+            disk: Mutex::new(disk),
             pool,
-            page_table,
+            page_table: PageTable::new(),
+            pending_free: Mutex::new(vec![]),
+            dirty_frames: Mutex::new(vec![]),
+            operation_latch: RwLock::new(()),
+            counters: Counters::default(),
         }
     }
-    pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<Buffer>, Error> {
-        if let Some(&buffer_id) = self.page_table.get(&page_id) {
-            let frame = &mut self.pool[buffer_id];
-            frame.usage_count += 1;
-            return Ok(frame.buffer.clone());
+
+    fn pin_cached(&self, page_id: PageId) -> Option<PageGuard> {
+        let buffer_id = self.page_table.get(page_id)?;
+        let frame = self.pool[buffer_id].lock().unwrap();
+        // the frame may have been reused between the lookup and the lock
+        if frame.buffer.page_id != page_id {
+            return None;
         }
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
-        let frame = &mut self.pool[buffer_id];
+        let guard = PageGuard::pin(&frame.buffer);
+        drop(frame);
+        self.pool.access(buffer_id);
+        Some(guard)
+    }
+
+    fn evict_frame(
+        &self,
+        disk: &mut DiskManager,
+    ) -> Result<(BufferId, MutexGuard<'_, Frame>), Error> {
+        let (buffer_id, mut frame) = match self.pool.evict() {
+            Some(victim) => victim,
+            None => {
+                Counters::bump(&self.counters.no_free_buffer);
+                return Err(Error::NoFreeBuffer(self.pin_report()));
+            }
+        };
         let evict_page_id = frame.buffer.page_id;
+        let pending_write = frame.pending_write;
+        if evict_page_id != PageId::INVALID_PAGE_ID {
+            Counters::bump(&self.counters.evictions);
+        }
+        let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
+        if pending_write.is_some() {
+            Counters::bump(&self.counters.write_backs);
+            // the log must reach the disk before the page it describes
+            disk.sync_log()?;
+            let page = &mut buffer.page.get_mut().unwrap()[..];
+            disk::set_checksum(page);
+            disk.write_page_data(evict_page_id, page)?;
+        }
+        buffer.page_id = PageId::INVALID_PAGE_ID;
+        frame.pending_write = None;
+        self.page_table.remove(evict_page_id);
+        Ok((buffer_id, frame))
+    }
+
+    pub fn fetch_page(&self, page_id: PageId) -> Result<PageGuard, Error> {
+        if let Some(buffer) = self.pin_cached(page_id) {
+            Counters::bump(&self.counters.hits);
+            return Ok(buffer);
+        }
+        let mut disk = self.disk.lock().unwrap();
+        // another thread may have read the page while we were waiting
+        if let Some(buffer) = self.pin_cached(page_id) {
+            Counters::bump(&self.counters.hits);
+            return Ok(buffer);
+        }
+        Counters::bump(&self.counters.misses);
+        let (buffer_id, mut frame) = self.evict_frame(&mut disk)?;
         {
-            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
-            if buffer.is_dirty.get() {
-                self.disk
-                    .write_page_data(evict_page_id, buffer.page.get_mut())?;
+            let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
+            let page = &mut buffer.page.get_mut().unwrap()[..];
+            disk.read_page_data(page_id, page)?;
+            if !disk::verify_checksum(page) {
+                return Err(Error::Corruption { page_id });
             }
             buffer.page_id = page_id;
-            buffer.is_dirty.set(false);
-            self.disk.read_page_data(page_id, buffer.page.get_mut())?;
         }
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
-        self.page_table.insert(page_id, buffer_id);
         self.page_table.insert(page_id, buffer_id);
-        Ok(page)
+        let guard = PageGuard::pin(&frame.buffer);
+        drop(frame);
+        self.pool.load(buffer_id, page_id);
+        Ok(guard)
     }
 
-    pub fn create_page(&mut self) -> Result<Rc<Buffer>, Error> {
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
-        let frame = &mut self.pool[buffer_id];
-        let evict_page_id = frame.buffer.page_id;
-        let page_id = {
-            let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
-            if buffer.is_dirty.get() {
-                self.disk
-                    .write_page_data(evict_page_id, buffer.page.get_mut())?;
-            }
-            self.page_table.remove(&evict_page_id);
-            let page_id = self.disk.allocate_page();
+    pub fn create_page(&self) -> Result<PageGuard, Error> {
+        let mut disk = self.disk.lock().unwrap();
+        let (buffer_id, mut frame) = self.evict_frame(&mut disk)?;
+        let page_id = disk.allocate_page()?;
+        {
+            let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
             *buffer = Buffer::default();
             buffer.page_id = page_id;
-            buffer.is_dirty.set(true);
-            frame.usage_count = 1;
-            page_id
-        };
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
+            *buffer.is_dirty.get_mut() = true;
+        }
+        self.dirty_frames.lock().unwrap().push(buffer_id);
         self.page_table.insert(page_id, buffer_id);
-        Ok(page)
+        let guard = PageGuard::pin(&frame.buffer);
+        drop(frame);
+        self.pool.load(buffer_id, page_id);
+        Ok(guard)
+    }
+
+    // The page goes back to the free list at the next log point, so that a
+    // crash in the middle of an operation never leaves a reachable page freed.
+    pub fn delete_page(&self, page_id: PageId) -> Result<(), Error> {
+        let _disk = self.disk.lock().unwrap();
+        if let Some(buffer_id) = self.page_table.get(page_id) {
+            let mut frame = self.pool[buffer_id].lock().unwrap();
+            match Arc::get_mut(&mut frame.buffer) {
+                Some(buffer) => *buffer = Buffer::default(),
+                // readers still holding the page keep the old buffer to
+                // themselves, and the frame gets a fresh one
+                None => frame.buffer = Arc::default(),
+            }
+            frame.pending_write = None;
+            self.page_table.remove(page_id);
+            drop(frame);
+            self.pool.free(buffer_id);
+        }
+        self.pending_free.lock().unwrap().push(page_id);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let disk = self.disk.lock().unwrap().stats();
+        let counters = &self.counters;
+        BufferStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            write_backs: counters.write_backs.load(Ordering::Relaxed),
+            no_free_buffer: counters.no_free_buffer.load(Ordering::Relaxed),
+            disk,
+        }
+    }
+
+    pub fn reset_stats(&self) {
+        let mut disk = self.disk.lock().unwrap();
+        disk.reset_stats();
+        let counters = &self.counters;
+        for counter in &[
+            &counters.hits,
+            &counters.misses,
+            &counters.evictions,
+            &counters.write_backs,
+            &counters.no_free_buffer,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn pin_report(&self) -> PinReport {
+        let mut report = PinReport::default();
+        for frame in &self.pool.buffers {
+            let frame = frame.lock().unwrap();
+            let pin_count = frame.buffer.pin_count.load(Ordering::Acquire);
+            if pin_count > 0 {
+                report.pinned.push((frame.buffer.page_id, pin_count));
+            } else if frame.buffer.is_dirty.load(Ordering::Acquire) {
+                report.unlogged += 1;
+            }
+        }
+        report
+    }
+
+    // Pages must be marked dirty through here, so that the next log point
+    // finds them.
+    pub fn mark_dirty(&self, buffer: &Buffer) {
+        if buffer.is_dirty.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // a page deleted while it was pinned has no frame left to log
+        if let Some(buffer_id) = self.page_table.get(buffer.page_id) {
+            self.dirty_frames.lock().unwrap().push(buffer_id);
+        }
+    }
+
+    // Must be held for the whole of a change that spans several pages, and
+    // not across a log point.
+    pub fn begin_operation(&self) -> RwLockReadGuard<'_, ()> {
+        self.operation_latch.read().unwrap()
+    }
+
+    // Logs every page changed since the last log point as one atomic group.
+    // It waits for running operations to finish, because recovery restores
+    // exactly the states captured here.
+    pub fn log_dirty_pages(&self) -> Result<(), Error> {
+        let _operations = self.operation_latch.write().unwrap();
+        self.log_dirty_frames()
+    }
+
+    fn log_dirty_frames(&self) -> Result<(), Error> {
+        // The dirty frames stay pinned until they are marked for writing, and
+        // their images are copied before the disk lock is taken, since a
+        // writer holding a page latch may be waiting for it. A page somebody
+        // latched for writing anyway is reported instead of waited for, and
+        // every page stays dirty for the next log point.
+        let mut buffer_ids = mem::take(&mut *self.dirty_frames.lock().unwrap());
+        buffer_ids.sort_unstable_by_key(|buffer_id| buffer_id.0);
+        buffer_ids.dedup();
+        let mut dirty = vec![];
+        for &buffer_id in &buffer_ids {
+            let frame = self.pool[buffer_id].lock().unwrap();
+            if frame.buffer.is_dirty.load(Ordering::Acquire) {
+                dirty.push((buffer_id, Arc::clone(&frame.buffer)));
+            }
+        }
+        let mut images = Vec::with_capacity(dirty.len());
+        for (_, buffer) in &dirty {
+            let mut page = match buffer.page.try_read() {
+                Ok(page) => {
+                    buffer.is_dirty.store(false, Ordering::Release);
+                    *page
+                }
+                Err(_) => {
+                    for (_, copied) in &dirty[..images.len()] {
+                        copied.is_dirty.store(true, Ordering::Release);
+                    }
+                    self.dirty_frames.lock().unwrap().extend(buffer_ids);
+                    return Err(Error::PageBusy {
+                        page_id: buffer.page_id,
+                    });
+                }
+            };
+            disk::set_checksum(&mut page[..]);
+            images.push((buffer.page_id, page));
+        }
+
+        let mut disk = self.disk.lock().unwrap();
+        let lsn = disk.log_lsn();
+        for (page_id, page) in &images {
+            disk.log_page(*page_id, &page[..])?;
+        }
+        for page_id in self.pending_free.lock().unwrap().drain(..) {
+            disk.deallocate_page(page_id);
+        }
+        if !images.is_empty() || disk.has_unlogged_changes() {
+            disk.log_commit()?;
+        }
+        for (buffer_id, _) in &dirty {
+            self.pool[*buffer_id].lock().unwrap().pending_write = Some(lsn);
+        }
+        Ok(())
+    }
+
+    // Logs a page nothing refers to yet, such as one the running operation
+    // created and has not linked, as a group of its own. The page can then be
+    // evicted before the operation reaches its log point; if the system
+    // crashes before that, recovery restores the page but nothing reaches it.
+    pub fn log_unlinked_page(&self, buffer: &Buffer) -> Result<(), Error> {
+        let mut page = {
+            let page = buffer.page.read().unwrap();
+            buffer.is_dirty.store(false, Ordering::Release);
+            *page
+        };
+        disk::set_checksum(&mut page[..]);
+        let mut disk = self.disk.lock().unwrap();
+        let lsn = disk.log_lsn();
+        disk.log_page(buffer.page_id, &page[..])?;
+        disk.log_commit()?;
+        let buffer_id = self
+            .page_table
+            .get(buffer.page_id)
+            .expect("a pinned page must stay in the pool");
+        self.pool[buffer_id].lock().unwrap().pending_write = Some(lsn);
+        Ok(())
     }
 
-    pub fn flush(&mut self) -> Result<(), Error> {
-        for (&page_id, &buffer_id) in self.page_table.iter() {
-            let frame = &self.pool[buffer_id];
-            let mut page = frame.buffer.page.borrow_mut();
-            self.disk.write_page_data(page_id, page.as_mut())?;
-            frame.buffer.is_dirty.set(false);
+    pub fn sync_log(&self) -> Result<(), Error> {
+        self.disk.lock().unwrap().sync_log()?;
+        Ok(())
+    }
+
+    pub fn catalog_page_id(&self) -> Option<PageId> {
+        self.disk.lock().unwrap().catalog_page_id()
+    }
+
+    pub fn set_catalog_page_id(&self, page_id: PageId) -> Result<(), Error> {
+        self.disk.lock().unwrap().set_catalog_page_id(page_id)?;
+        Ok(())
+    }
+
+    pub fn clog_page_id(&self) -> Option<PageId> {
+        self.disk.lock().unwrap().clog_page_id()
+    }
+
+    pub fn set_clog_page_id(&self, page_id: PageId) -> Result<(), Error> {
+        self.disk.lock().unwrap().set_clog_page_id(page_id)?;
+        Ok(())
+    }
+
+    pub fn next_xid(&self) -> u64 {
+        self.disk.lock().unwrap().next_xid()
+    }
+
+    pub fn set_next_xid(&self, next_xid: u64) -> Result<(), Error> {
+        self.disk.lock().unwrap().set_next_xid(next_xid)?;
+        Ok(())
+    }
+
+    // Returns `None` if nothing was logged since the last checkpoint.
+    pub fn begin_checkpoint(&self) -> Option<Checkpoint> {
+        let disk = self.disk.lock().unwrap();
+        if !disk.needs_checkpoint() {
+            return None;
+        }
+        Some(Checkpoint {
+            redo_lsn: disk.log_lsn(),
+        })
+    }
+
+    // Frames whose logged image has not reached the heap file, in page id
+    // order. Frames changed since they were logged hold unlogged changes and
+    // are left out.
+    fn pending_frames(&self, is_due: impl Fn(&Frame) -> bool) -> Vec<WriteBack> {
+        let mut pending = vec![];
+        for (idx, frame) in self.pool.buffers.iter().enumerate() {
+            let frame = frame.lock().unwrap();
+            if let Some(lsn) = frame.pending_write {
+                if is_due(&frame) && !frame.buffer.is_dirty.load(Ordering::Acquire) {
+                    pending.push(WriteBack {
+                        buffer_id: BufferId(idx),
+                        lsn,
+                        buffer: Arc::clone(&frame.buffer),
+                        page: None,
+                    });
+                }
+            }
+        }
+        pending.sort_unstable_by_key(|write_back| write_back.buffer.page_id.to_u64());
+        pending
+    }
+
+    // The operation latch must be held exclusively, so that the copies are
+    // exactly the logged images. A page somebody latched for writing anyway
+    // is reported instead of waited for.
+    fn copy_images(&self, pending: &mut [WriteBack]) -> Result<(), Error> {
+        for write_back in pending {
+            let buffer = &write_back.buffer;
+            let mut page = match buffer.page.try_read() {
+                Ok(page) => *page,
+                Err(_) => {
+                    return Err(Error::PageBusy {
+                        page_id: buffer.page_id,
+                    })
+                }
+            };
+            disk::set_checksum(&mut page[..]);
+            write_back.page = Some(page);
+        }
+        Ok(())
+    }
+
+    // Every change of `pending_write` happens under the disk lock, so the
+    // frames checked here stay as they are until they are written.
+    fn write_images(&self, disk: &mut DiskManager, pending: &[WriteBack]) -> Result<(), Error> {
+        // the page may have been deleted or logged again in the meantime
+        let is_current = |write_back: &WriteBack| {
+            let frame = self.pool[write_back.buffer_id].lock().unwrap();
+            Arc::ptr_eq(&frame.buffer, &write_back.buffer)
+                && frame.pending_write == Some(write_back.lsn)
+        };
+        let current: Vec<_> = pending
+            .iter()
+            .filter(|write_back| is_current(write_back))
+            .collect();
+        let pages: Vec<_> = current
+            .iter()
+            .map(|write_back| {
+                let page = write_back.page.as_ref().unwrap();
+                (write_back.buffer.page_id, &page[..])
+            })
+            .collect();
+        // the log must reach the disk before the pages it describes
+        disk.sync_log()?;
+        disk.write_pages(&pages)?;
+        for write_back in current {
+            self.pool[write_back.buffer_id]
+                .lock()
+                .unwrap()
+                .pending_write = None;
+        }
+        Ok(())
+    }
+
+    fn has_pending_frames(&self, is_due: impl Fn(&Frame) -> bool) -> bool {
+        self.pool.buffers.iter().any(|frame| {
+            let frame = frame.lock().unwrap();
+            frame.pending_write.is_some() && is_due(&frame)
+        })
+    }
+
+    // Writes up to `max_pages` of the pages the checkpoint waits for, in page
+    // id order, and returns whether the checkpoint is complete. Pages that
+    // were changed again after being logged are left to a later step, by
+    // which time they have been logged again.
+    pub fn checkpoint_step(
+        &self,
+        checkpoint: &Checkpoint,
+        max_pages: usize,
+    ) -> Result<bool, Error> {
+        let is_due =
+            |frame: &Frame| matches!(frame.pending_write, Some(lsn) if lsn < checkpoint.redo_lsn);
+        let mut pending = {
+            let _operations = self.operation_latch.write().unwrap();
+            let mut pending = self.pending_frames(is_due);
+            pending.truncate(max_pages);
+            self.copy_images(&mut pending)?;
+            pending
+        };
+        let mut disk = self.disk.lock().unwrap();
+        self.write_images(&mut disk, &pending)?;
+        pending.clear();
+        if self.has_pending_frames(is_due) {
+            return Ok(false);
+        }
+        disk.sync()?;
+        disk.log_checkpoint(checkpoint.redo_lsn)?;
+        Ok(true)
+    }
+
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        self.log_dirty_pages()?;
+        let checkpoint = match self.begin_checkpoint() {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(()),
+        };
+        while !self.checkpoint_step(&checkpoint, usize::MAX)? {
+            self.log_dirty_pages()?;
         }
-        self.disk.sync()?;
         Ok(())
     }
+
+    // Writes the page if it has changes the heap file lacks. Like every
+    // write-back it happens at a log point, so other changed pages are
+    // logged as well.
+    pub fn flush_page(&self, page_id: PageId) -> Result<(), Error> {
+        let _operations = self.operation_latch.write().unwrap();
+        self.log_dirty_frames()?;
+        let mut pending = self.pending_frames(|frame| frame.buffer.page_id == page_id);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        self.copy_images(&mut pending)?;
+        let mut disk = self.disk.lock().unwrap();
+        self.write_images(&mut disk, &pending)?;
+        disk.sync()?;
+        Ok(())
+    }
+
+    // Writes every page with changes the heap file lacks, and empties the
+    // log once nothing depends on it.
+    pub fn flush_all(&self) -> Result<(), Error> {
+        let _operations = self.operation_latch.write().unwrap();
+        self.log_dirty_frames()?;
+        let mut pending = self.pending_frames(|_| true);
+        self.copy_images(&mut pending)?;
+        let mut disk = self.disk.lock().unwrap();
+        self.write_images(&mut disk, &pending)?;
+        disk.sync()?;
+        // a page changed without the operation latch keeps its logged image
+        // in the log
+        if !self.has_pending_frames(|_| true) {
+            disk.truncate_log()?;
+        }
+        Ok(())
+    }
+}
+
+// A logged page image on its way to the heap file.
+struct WriteBack {
+    buffer_id: BufferId,
+    lsn: Lsn,
+    buffer: Arc<Buffer>,
+    page: Option<Page>,
+}
+
+// Recovery redoes the log from the start of the last complete checkpoint,
+// so checkpointing bounds both the work left after a crash and the size of
+// the log.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    redo_lsn: Lsn,
+}
+
+// Checkpoints in the background, writing at most `pages_per_round` pages
+// every `interval`, so that foreground operations rarely have to write pages
+// back themselves.
+pub struct BackgroundWriter {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl BackgroundWriter {
+    pub fn spawn(
+        bufmgr: Arc<BufferPoolManager>,
+        interval: Duration,
+        pages_per_round: usize,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut checkpoint = None;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if checkpoint.is_none() {
+                    checkpoint = bufmgr.begin_checkpoint();
+                }
+                if let Some(current) = &checkpoint {
+                    if bufmgr.checkpoint_step(current, pages_per_round)? {
+                        checkpoint = None;
+                    }
+                }
+            }
+            Ok(())
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+
+    // Returns the error the writer stopped on, if any.
+    pub fn stop(mut self) -> Result<(), Error> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), Error> {
+        drop(self.stop.take());
+        match self.handle.take() {
+            Some(handle) => handle.join().expect("background writer panicked"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{prelude::*, SeekFrom};
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.page.write().unwrap()[..5].copy_from_slice(b"hello");
+            buffer.page_id
+        };
+        bufmgr.flush_all().unwrap();
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        assert_eq!(b"hello", &buffer.page.read().unwrap()[..5]);
+        drop(buffer);
+        drop(bufmgr);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&data_file_path)
+            .unwrap();
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64 * page_id.to_u64() + 1))
+            .unwrap();
+        file.write_all(b"E").unwrap();
+        drop(file);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        assert!(matches!(
+            bufmgr.fetch_page(page_id),
+            Err(Error::Corruption { page_id: corrupted }) if corrupted == page_id
+        ));
+    }
+
+    #[test]
+    fn test_recovery() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let buffer = bufmgr.create_page().unwrap();
+        buffer.page.write().unwrap()[..5].copy_from_slice(b"hello");
+        bufmgr.log_dirty_pages().unwrap();
+        buffer.page.write().unwrap()[..5].copy_from_slice(b"world");
+        bufmgr.mark_dirty(&buffer);
+        let page_id = buffer.page_id;
+        drop(buffer);
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        assert_eq!(b"hello", &buffer.page.read().unwrap()[..5]);
+    }
+
+    #[test]
+    fn test_pin_report() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("test.rly")).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let first = bufmgr.create_page().unwrap();
+        let second = bufmgr.create_page().unwrap();
+        bufmgr.log_dirty_pages().unwrap();
+        let first_again = first.clone();
+        assert_eq!(2, first_again.pin_count());
+
+        let report = match bufmgr.create_page() {
+            Err(Error::NoFreeBuffer(report)) => report,
+            other => panic!("expected NoFreeBuffer, got {:?}", other),
+        };
+        let mut pinned = report.pinned.clone();
+        pinned.sort_unstable_by_key(|(page_id, _)| page_id.to_u64());
+        assert_eq!(vec![(first.page_id, 2), (second.page_id, 1)], pinned);
+        assert_eq!(0, report.unlogged);
+
+        drop(first);
+        drop(first_again);
+        bufmgr.create_page().unwrap();
+        assert_eq!(vec![(second.page_id, 1)], bufmgr.pin_report().pinned);
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("test.rly")).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let first = bufmgr.create_page().unwrap().page_id;
+        bufmgr.log_dirty_pages().unwrap();
+        let second = bufmgr.create_page().unwrap().page_id;
+        bufmgr.log_dirty_pages().unwrap();
+        bufmgr.reset_stats();
+        assert_eq!(BufferStats::default(), bufmgr.stats());
+
+        drop(bufmgr.fetch_page(second).unwrap());
+        drop(bufmgr.fetch_page(first).unwrap());
+        drop(bufmgr.fetch_page(first).unwrap());
+        let stats = bufmgr.stats();
+        assert_eq!((2, 1), (stats.hits, stats.misses));
+        assert_eq!((1, 1), (stats.evictions, stats.write_backs));
+        assert_eq!((1, 1), (stats.disk.page_reads, stats.disk.page_writes));
+
+        let _pinned = bufmgr.fetch_page(first).unwrap();
+        assert!(bufmgr.fetch_page(second).is_err());
+        assert_eq!(1, bufmgr.stats().no_free_buffer);
+        assert!(bufmgr.stats().to_string().contains("60.0% hit ratio"));
+    }
+
+    fn pending_writes(bufmgr: &BufferPoolManager) -> Vec<PageId> {
+        let mut page_ids: Vec<_> = bufmgr
+            .pool
+            .buffers
+            .iter()
+            .map(|frame| frame.lock().unwrap())
+            .filter(|frame| frame.pending_write.is_some())
+            .map(|frame| frame.buffer.page_id)
+            .collect();
+        page_ids.sort_unstable_by_key(|page_id| page_id.to_u64());
+        page_ids
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(8));
+        let mut page_ids = vec![];
+        for i in 0..3u8 {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.page.write().unwrap()[0] = i;
+            page_ids.push(buffer.page_id);
+        }
+        bufmgr.log_dirty_pages().unwrap();
+        bufmgr.reset_stats();
+
+        let checkpoint = bufmgr.begin_checkpoint().unwrap();
+        {
+            // a page changed after it was logged has to wait
+            let buffer = bufmgr.fetch_page(page_ids[1]).unwrap();
+            buffer.page.write().unwrap()[0] = 10;
+            bufmgr.mark_dirty(&buffer);
+        }
+        assert!(!bufmgr.checkpoint_step(&checkpoint, 1).unwrap());
+        assert_eq!(page_ids[1..], pending_writes(&bufmgr)[..]);
+        assert!(!bufmgr.checkpoint_step(&checkpoint, 10).unwrap());
+        assert_eq!(page_ids[1..2], pending_writes(&bufmgr)[..]);
+        bufmgr.log_dirty_pages().unwrap();
+        assert!(bufmgr.checkpoint_step(&checkpoint, 10).unwrap());
+        assert_eq!(2, bufmgr.stats().disk.page_writes);
+        assert!(bufmgr.begin_checkpoint().is_some());
+        bufmgr.checkpoint().unwrap();
+        assert!(pending_writes(&bufmgr).is_empty());
+        assert!(bufmgr.begin_checkpoint().is_none());
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(8));
+        for (page_id, expected) in page_ids.iter().zip(&[0, 10, 2]) {
+            let buffer = bufmgr.fetch_page(*page_id).unwrap();
+            assert_eq!(*expected, buffer.page.read().unwrap()[0]);
+        }
+    }
+
+    #[test]
+    fn test_background_writer() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("test.rly")).unwrap();
+        let bufmgr = Arc::new(BufferPoolManager::new(disk, BufferPool::new(8)));
+        let writer = BackgroundWriter::spawn(Arc::clone(&bufmgr), Duration::from_millis(1), 2);
+        for _ in 0..5 {
+            bufmgr.create_page().unwrap();
+        }
+        bufmgr.log_dirty_pages().unwrap();
+        for _ in 0..1000 {
+            if pending_writes(&bufmgr).is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(pending_writes(&bufmgr).is_empty());
+        writer.stop().unwrap();
+    }
+
+    #[test]
+    fn test_flush() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("test.rly")).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let first = bufmgr.create_page().unwrap();
+        let second = bufmgr.create_page().unwrap();
+        bufmgr.flush_all().unwrap();
+        bufmgr.reset_stats();
+        bufmgr.flush_all().unwrap();
+        bufmgr.flush_page(first.page_id).unwrap();
+        assert_eq!(0, bufmgr.stats().disk.page_writes);
+
+        first.page.write().unwrap()[0] = 1;
+        bufmgr.mark_dirty(&first);
+        second.page.write().unwrap()[0] = 2;
+        bufmgr.mark_dirty(&second);
+        let reading = first.page.read().unwrap();
+        bufmgr.flush_page(first.page_id).unwrap();
+        drop(reading);
+        assert_eq!(1, bufmgr.stats().disk.page_writes);
+        assert_eq!(vec![second.page_id], pending_writes(&bufmgr));
+
+        let writing = second.page.write().unwrap();
+        assert!(matches!(
+            bufmgr.flush_all(),
+            Err(Error::PageBusy { page_id }) if page_id == second.page_id
+        ));
+        drop(writing);
+        bufmgr.flush_all().unwrap();
+        assert_eq!(2, bufmgr.stats().disk.page_writes);
+        assert!(pending_writes(&bufmgr).is_empty());
+
+        // a dirty page latched for writing cannot be logged yet
+        let mut writing = first.page.write().unwrap();
+        writing[0] = 3;
+        bufmgr.mark_dirty(&first);
+        second.page.write().unwrap()[0] = 4;
+        bufmgr.mark_dirty(&second);
+        assert!(matches!(
+            bufmgr.log_dirty_pages(),
+            Err(Error::PageBusy { page_id }) if page_id == first.page_id
+        ));
+        assert!(matches!(
+            bufmgr.flush_page(second.page_id),
+            Err(Error::PageBusy { page_id }) if page_id == first.page_id
+        ));
+        assert!(pending_writes(&bufmgr).is_empty());
+        drop(writing);
+        bufmgr.flush_all().unwrap();
+        assert_eq!(4, bufmgr.stats().disk.page_writes);
+        assert!(!first.is_dirty.load(Ordering::Acquire));
+        assert!(!second.is_dirty.load(Ordering::Acquire));
+    }
+
+    fn concurrent_fetch(policy: Policy) {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("test.rly")).unwrap();
+        let bufmgr = Arc::new(BufferPoolManager::new(
+            disk,
+            BufferPool::with_policy(4, policy),
+        ));
+        let mut page_ids = vec![];
+        for i in 0..16u64 {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.page.write().unwrap()[..8].copy_from_slice(&i.to_le_bytes());
+            page_ids.push(buffer.page_id);
+            drop(buffer);
+            bufmgr.log_dirty_pages().unwrap();
+        }
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let page_ids = page_ids.clone();
+                std::thread::spawn(move || {
+                    for n in 0..500 {
+                        let i = (n * 7 + t * 3) % page_ids.len();
+                        let buffer = bufmgr.fetch_page(page_ids[i]).unwrap();
+                        assert_eq!(page_ids[i], buffer.page_id);
+                        let page = buffer.page.read().unwrap();
+                        assert_eq!((i as u64).to_le_bytes(), page[..8]);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_concurrent_fetch() {
+        for &policy in &[Policy::Clock, Policy::Lru, Policy::LruK(2), Policy::TwoQ] {
+            concurrent_fetch(policy);
+        }
+    }
+
+    #[test]
+    fn test_dirty_frames() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::open(dir.path().join("test.rly")).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(8));
+        let buffers: Vec<_> = (0..4).map(|_| bufmgr.create_page().unwrap()).collect();
+        bufmgr.log_dirty_pages().unwrap();
+        assert!(bufmgr.dirty_frames.lock().unwrap().is_empty());
+
+        bufmgr.reset_stats();
+        bufmgr.mark_dirty(&buffers[2]);
+        bufmgr.mark_dirty(&buffers[2]);
+        bufmgr.mark_dirty(&buffers[0]);
+        assert_eq!(2, bufmgr.dirty_frames.lock().unwrap().len());
+        bufmgr.log_dirty_pages().unwrap();
+        assert_eq!(2, bufmgr.stats().disk.log_pages);
+        assert!(bufmgr.dirty_frames.lock().unwrap().is_empty());
+        assert!(buffers.iter().all(|buffer| !buffer.is_dirty()));
+    }
 }
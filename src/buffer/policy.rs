@@ -0,0 +1,402 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use super::BufferId;
+use crate::disk::PageId;
+
+// Decides which frame to reuse on a miss. The pool reports every load, hit
+// and freed frame; `victim` must only return a frame `is_evictable` accepts.
+pub trait ReplacementPolicy: Send {
+    fn load(&mut self, buffer_id: BufferId, page_id: PageId);
+    fn access(&mut self, buffer_id: BufferId);
+    fn free(&mut self, buffer_id: BufferId);
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId>;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    Clock,
+    Lru,
+    // evicts by the time of the k-th most recent access
+    LruK(usize),
+    TwoQ,
+}
+
+impl Policy {
+    pub(super) fn build(self, pool_size: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            Policy::Clock => Box::new(Clock::new(pool_size)),
+            Policy::Lru => Box::new(Lru::new(pool_size)),
+            Policy::LruK(k) => Box::new(LruK::new(pool_size, k)),
+            Policy::TwoQ => Box::new(TwoQ::new(pool_size)),
+        }
+    }
+}
+
+// Frames ordered by a key, such as the time of their last access, kept up to
+// date on every access so that a victim is found without sorting the pool.
+struct Ordered<K> {
+    keys: Vec<Option<K>>,
+    order: BTreeSet<(K, usize)>,
+}
+
+impl<K: Ord + Copy> Ordered<K> {
+    fn new(pool_size: usize) -> Self {
+        Self {
+            keys: vec![None; pool_size],
+            order: BTreeSet::new(),
+        }
+    }
+
+    fn set(&mut self, buffer_id: BufferId, key: K) {
+        self.remove(buffer_id);
+        self.keys[buffer_id.0] = Some(key);
+        self.order.insert((key, buffer_id.0));
+    }
+
+    fn remove(&mut self, buffer_id: BufferId) {
+        if let Some(key) = self.keys[buffer_id.0].take() {
+            self.order.remove(&(key, buffer_id.0));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn first(&self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        self.order
+            .iter()
+            .map(|&(_, idx)| BufferId(idx))
+            .find(|&id| is_evictable(id))
+    }
+}
+
+// A page's usage count saturates, so that a page which was hot once does not
+// outlive many sweeps after it has gone cold.
+const MAX_USAGE_COUNT: u64 = 5;
+
+pub struct Clock {
+    usage_counts: Vec<u64>,
+    hand: usize,
+}
+
+impl Clock {
+    fn new(pool_size: usize) -> Self {
+        Self {
+            usage_counts: vec![0; pool_size],
+            hand: 0,
+        }
+    }
+}
+
+impl ReplacementPolicy for Clock {
+    fn load(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.usage_counts[buffer_id.0] = 1;
+    }
+
+    fn access(&mut self, buffer_id: BufferId) {
+        let usage_count = &mut self.usage_counts[buffer_id.0];
+        *usage_count = (*usage_count + 1).min(MAX_USAGE_COUNT);
+    }
+
+    fn free(&mut self, buffer_id: BufferId) {
+        self.usage_counts[buffer_id.0] = 0;
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        let pool_size = self.usage_counts.len();
+        let mut consecutive_pinned = 0;
+        loop {
+            let victim_id = BufferId(self.hand);
+            self.hand = (self.hand + 1) % pool_size;
+            if !is_evictable(victim_id) {
+                consecutive_pinned += 1;
+                if consecutive_pinned >= pool_size {
+                    return None;
+                }
+                continue;
+            }
+            consecutive_pinned = 0;
+            let usage_count = &mut self.usage_counts[victim_id.0];
+            if *usage_count == 0 {
+                return Some(victim_id);
+            }
+            *usage_count -= 1;
+        }
+    }
+}
+
+pub struct Lru {
+    last_access: Ordered<u64>,
+    now: u64,
+}
+
+impl Lru {
+    fn new(pool_size: usize) -> Self {
+        let mut last_access = Ordered::new(pool_size);
+        for idx in 0..pool_size {
+            last_access.set(BufferId(idx), 0);
+        }
+        Self {
+            last_access,
+            now: 0,
+        }
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn load(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.access(buffer_id);
+    }
+
+    fn access(&mut self, buffer_id: BufferId) {
+        self.now += 1;
+        self.last_access.set(buffer_id, self.now);
+    }
+
+    fn free(&mut self, buffer_id: BufferId) {
+        self.last_access.set(buffer_id, 0);
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        self.last_access.first(is_evictable)
+    }
+}
+
+// Pages with fewer than k accesses go first, least recently used among
+// them, so a page touched once by a scan never pushes out one in steady use.
+pub struct LruK {
+    k: usize,
+    history: Vec<VecDeque<u64>>,
+    // whether a frame has k accesses, then the k-th most recent one or, for
+    // a frame with fewer, the most recent one
+    order: Ordered<(bool, u64)>,
+    now: u64,
+}
+
+impl LruK {
+    fn new(pool_size: usize, k: usize) -> Self {
+        assert!(k > 0, "LRU-K needs k of at least 1");
+        let mut history = vec![];
+        history.resize_with(pool_size, VecDeque::new);
+        let mut order = Ordered::new(pool_size);
+        for idx in 0..pool_size {
+            order.set(BufferId(idx), (false, 0));
+        }
+        Self {
+            k,
+            history,
+            order,
+            now: 0,
+        }
+    }
+
+    fn update_order(&mut self, buffer_id: BufferId) {
+        let history = &self.history[buffer_id.0];
+        let key = match history.get(self.k - 1) {
+            Some(&kth) => (true, kth),
+            None => (false, history.front().copied().unwrap_or(0)),
+        };
+        self.order.set(buffer_id, key);
+    }
+}
+
+impl ReplacementPolicy for LruK {
+    fn load(&mut self, buffer_id: BufferId, _page_id: PageId) {
+        self.history[buffer_id.0].clear();
+        self.access(buffer_id);
+    }
+
+    fn access(&mut self, buffer_id: BufferId) {
+        self.now += 1;
+        let history = &mut self.history[buffer_id.0];
+        if history.len() == self.k {
+            history.pop_back();
+        }
+        history.push_front(self.now);
+        self.update_order(buffer_id);
+    }
+
+    fn free(&mut self, buffer_id: BufferId) {
+        self.history[buffer_id.0].clear();
+        self.update_order(buffer_id);
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        self.order.first(is_evictable)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queue {
+    Free,
+    // first seen recently; hits here are treated as part of the same use
+    In,
+    // seen again after leaving `In`
+    Main,
+}
+
+// 2Q: new pages enter a FIFO queue, and only pages that come back after
+// being evicted from it, which are remembered by page id, join the LRU
+// queue. A scan therefore only ever replaces pages in the FIFO queue.
+pub struct TwoQ {
+    queues: Vec<Queue>,
+    page_ids: Vec<PageId>,
+    // the frames of each queue by the time they entered it or, in `Main`,
+    // were last accessed
+    free: Ordered<u64>,
+    fifo: Ordered<u64>,
+    main: Ordered<u64>,
+    now: u64,
+    max_in: usize,
+    ghosts: VecDeque<PageId>,
+    max_ghosts: usize,
+}
+
+impl TwoQ {
+    fn new(pool_size: usize) -> Self {
+        let mut free = Ordered::new(pool_size);
+        for idx in 0..pool_size {
+            free.set(BufferId(idx), 0);
+        }
+        Self {
+            queues: vec![Queue::Free; pool_size],
+            page_ids: vec![PageId::INVALID_PAGE_ID; pool_size],
+            free,
+            fifo: Ordered::new(pool_size),
+            main: Ordered::new(pool_size),
+            now: 0,
+            max_in: (pool_size / 4).max(1),
+            ghosts: VecDeque::new(),
+            max_ghosts: (pool_size / 2).max(1),
+        }
+    }
+
+    fn ordered(&mut self, queue: Queue) -> &mut Ordered<u64> {
+        match queue {
+            Queue::Free => &mut self.free,
+            Queue::In => &mut self.fifo,
+            Queue::Main => &mut self.main,
+        }
+    }
+
+    fn move_to(&mut self, buffer_id: BufferId, queue: Queue) {
+        let from = self.queues[buffer_id.0];
+        self.ordered(from).remove(buffer_id);
+        self.now += 1;
+        let now = self.now;
+        self.ordered(queue).set(buffer_id, now);
+        self.queues[buffer_id.0] = queue;
+    }
+
+    fn remember(&mut self, page_id: PageId) {
+        if self.ghosts.len() == self.max_ghosts {
+            self.ghosts.pop_front();
+        }
+        self.ghosts.push_back(page_id);
+    }
+}
+
+impl ReplacementPolicy for TwoQ {
+    fn load(&mut self, buffer_id: BufferId, page_id: PageId) {
+        let queue = match self.ghosts.iter().position(|&ghost| ghost == page_id) {
+            Some(idx) => {
+                self.ghosts.remove(idx);
+                Queue::Main
+            }
+            None => Queue::In,
+        };
+        self.move_to(buffer_id, queue);
+        self.page_ids[buffer_id.0] = page_id;
+    }
+
+    fn access(&mut self, buffer_id: BufferId) {
+        if self.queues[buffer_id.0] == Queue::Main {
+            self.move_to(buffer_id, Queue::Main);
+        }
+    }
+
+    fn free(&mut self, buffer_id: BufferId) {
+        self.move_to(buffer_id, Queue::Free);
+    }
+
+    fn victim(&mut self, is_evictable: &dyn Fn(BufferId) -> bool) -> Option<BufferId> {
+        if let Some(buffer_id) = self.free.first(is_evictable) {
+            return Some(buffer_id);
+        }
+        let victim_id = if self.fifo.len() >= self.max_in {
+            self.fifo
+                .first(is_evictable)
+                .or_else(|| self.main.first(is_evictable))
+        } else {
+            self.main
+                .first(is_evictable)
+                .or_else(|| self.fifo.first(is_evictable))
+        }?;
+        if self.queues[victim_id.0] == Queue::In {
+            self.remember(self.page_ids[victim_id.0]);
+        }
+        Some(victim_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_all(policy: &mut dyn ReplacementPolicy, page_ids: &[u64]) {
+        for (idx, &page_id) in page_ids.iter().enumerate() {
+            policy.load(BufferId(idx), PageId(page_id));
+        }
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(3);
+        load_all(&mut lru, &[10, 11, 12]);
+        lru.access(BufferId(0));
+        assert_eq!(Some(BufferId(1)), lru.victim(&|_| true));
+        assert_eq!(Some(BufferId(2)), lru.victim(&|id| id != BufferId(1)));
+        assert_eq!(None, lru.victim(&|_| false));
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut clock = Clock::new(3);
+        load_all(&mut clock, &[10, 11, 12]);
+        clock.access(BufferId(0));
+        clock.access(BufferId(2));
+        assert_eq!(Some(BufferId(1)), clock.victim(&|_| true));
+        assert_eq!(None, clock.victim(&|_| false));
+    }
+
+    #[test]
+    fn test_lru_k() {
+        let mut lru_k = LruK::new(3, 2);
+        load_all(&mut lru_k, &[10, 11, 12]);
+        lru_k.access(BufferId(0));
+        lru_k.access(BufferId(1));
+        // buffer 2 was used last, but only once
+        assert_eq!(Some(BufferId(2)), lru_k.victim(&|_| true));
+        assert_eq!(Some(BufferId(0)), lru_k.victim(&|id| id != BufferId(2)));
+    }
+
+    #[test]
+    fn test_two_q_resists_scans() {
+        let mut two_q = TwoQ::new(4);
+        load_all(&mut two_q, &[1, 2, 3, 4]);
+        // page 1 leaves the FIFO queue once and is remembered when it
+        // comes back
+        assert_eq!(Some(BufferId(0)), two_q.victim(&|_| true));
+        two_q.load(BufferId(0), PageId(5));
+        assert_eq!(Some(BufferId(1)), two_q.victim(&|_| true));
+        two_q.load(BufferId(1), PageId(1));
+        assert_eq!(Queue::Main, two_q.queues[1]);
+        for page_id in 10..30 {
+            let victim = two_q.victim(&|_| true).unwrap();
+            assert_ne!(BufferId(1), victim);
+            two_q.load(victim, PageId(page_id));
+        }
+    }
+}
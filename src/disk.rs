@@ -1,11 +1,74 @@
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
-use std::io::{self, prelude::*, SeekFrom};
+use std::io;
+use std::mem::{self, size_of};
 use std::path::Path;
 
 use zerocopy::{AsBytes, FromBytes};
 
+use crate::wal::{Lsn, Wal};
+
+mod heap;
+
+use heap::HeapFile;
+
 pub const PAGE_SIZE: usize = 4096;
+pub const HEADER_PAGE_ID: PageId = PageId(0);
+pub const MAGIC: [u8; 8] = *b"RELLYDB\0";
+pub const FORMAT_VERSION: u32 = 4;
+pub const CHECKSUM_SIZE: usize = size_of::<u32>();
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a relly database file")]
+    InvalidMagic,
+    #[error("unsupported format version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("page size mismatch: file uses {0} bytes, expected {}", PAGE_SIZE)]
+    PageSizeMismatch(u32),
+    #[error("checksum mismatch in page {page_id:?}")]
+    Corruption { page_id: PageId },
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+pub fn set_checksum(page: &mut [u8]) {
+    let (body, checksum) = page.split_at_mut(page.len() - CHECKSUM_SIZE);
+    checksum.copy_from_slice(&crc32(body).to_le_bytes());
+}
+
+pub fn verify_checksum(page: &[u8]) -> bool {
+    let (body, checksum) = page.split_at(page.len() - CHECKSUM_SIZE);
+    checksum == crc32(body).to_le_bytes()
+}
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, FromBytes, AsBytes)]
 #[repr(C)]
@@ -46,50 +109,479 @@ impl From<&[u8]> for PageId {
     }
 }
 
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    page_size: u32,
+    free_page_id: PageId,
+    catalog_page_id: PageId,
+    next_xid: u64,
+    clog_page_id: PageId,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            free_page_id: PageId::INVALID_PAGE_ID,
+            catalog_page_id: PageId::INVALID_PAGE_ID,
+            next_xid: 0,
+            clog_page_id: PageId::INVALID_PAGE_ID,
+        }
+    }
+}
+
+impl Header {
+    fn validate(&self) -> Result<(), Error> {
+        if self.magic != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        if self.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(self.version));
+        }
+        if self.page_size != PAGE_SIZE as u32 {
+            return Err(Error::PageSizeMismatch(self.page_size));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DiskStats {
+    pub page_reads: u64,
+    pub page_writes: u64,
+    pub log_pages: u64,
+    pub log_syncs: u64,
+    pub syncs: u64,
+    // page writes issued together for runs of consecutive pages
+    pub vectored_writes: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiskOptions {
+    // Bypasses the OS page cache for the heap file, which would otherwise
+    // hold a second copy of the pages in the buffer pool. Linux only, and
+    // not every file system supports it.
+    pub direct_io: bool,
+}
+
 pub struct DiskManager {
-    heap_file: File,
+    heap: HeapFile,
     next_page_id: u64,
+    header: Header,
+    // Changes of the header and pages freed since the last log group, which
+    // join the next group so that allocating or freeing a page costs no log
+    // sync of its own.
+    header_changed: bool,
+    free_pages: Vec<(PageId, [u8; PAGE_SIZE])>,
+    // header and free pages logged but kept from the heap file until the log
+    // is synced
+    unwritten: Vec<(PageId, [u8; PAGE_SIZE])>,
+    wal: Wal,
+    // nothing logged before this lsn is missing from the heap file
+    checkpointed_lsn: Lsn,
+    stats: DiskStats,
 }
 
 impl DiskManager {
-    pub fn new(heap_file: File) -> io::Result<Self> {
-        let heap_file_size = heap_file.metadata()?.len();
-        let next_page_id = heap_file_size / PAGE_SIZE as u64;
-        Ok(Self {
-            heap_file,
-            next_page_id,
-        })
+    pub fn new(heap_file: File, wal_file: File) -> Result<Self, Error> {
+        Self::from_files(HeapFile::new(heap_file, false), wal_file)
     }
 
-    pub fn open(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
-        let heap_file = OpenOptions::new()
+    fn from_files(heap: HeapFile, wal_file: File) -> Result<Self, Error> {
+        let mut disk = Self {
+            heap,
+            next_page_id: 0,
+            header: Header::default(),
+            header_changed: false,
+            free_pages: vec![],
+            unwritten: vec![],
+            wal: Wal::open(wal_file)?,
+            checkpointed_lsn: 0,
+            stats: DiskStats::default(),
+        };
+        disk.recover()?;
+        let heap_file_size = disk.heap.len()?;
+        disk.next_page_id = heap_file_size / PAGE_SIZE as u64;
+        if heap_file_size == 0 {
+            disk.next_page_id = HEADER_PAGE_ID.to_u64() + 1;
+            disk.write_header()?;
+        } else {
+            disk.read_header()?;
+        }
+        Ok(disk)
+    }
+
+    pub fn open(heap_file_path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::open_with_options(heap_file_path, DiskOptions::default())
+    }
+
+    pub fn open_with_options(
+        heap_file_path: impl AsRef<Path>,
+        options: DiskOptions,
+    ) -> Result<Self, Error> {
+        let mut wal_file_path = heap_file_path.as_ref().as_os_str().to_owned();
+        wal_file_path.push(".wal");
+        let mut heap_options = OpenOptions::new();
+        heap_options
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false);
+        if options.direct_io {
+            heap::set_direct_io(&mut heap_options)?;
+        }
+        let heap_file = heap_options.open(heap_file_path)?;
+        let wal_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(heap_file_path)?;
-        Self::new(heap_file)
+            .truncate(false)
+            .open(wal_file_path)?;
+        Self::from_files(HeapFile::new(heap_file, options.direct_io), wal_file)
+    }
+
+    fn recover(&mut self) -> io::Result<()> {
+        let heap = &self.heap;
+        self.wal
+            .replay(|page_id, page| heap.write_page(page_id, page))?;
+        self.heap.sync()?;
+        self.wal.truncate()
+    }
+
+    fn read_header(&mut self) -> Result<(), Error> {
+        let mut data = [0u8; PAGE_SIZE];
+        self.read_page_data(HEADER_PAGE_ID, &mut data)?;
+        let mut header = Header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&data[..size_of::<Header>()]);
+        header.validate()?;
+        if !verify_checksum(&data) {
+            return Err(Error::Corruption {
+                page_id: HEADER_PAGE_ID,
+            });
+        }
+        self.header = header;
+        Ok(())
+    }
+
+    fn header_page(&self) -> [u8; PAGE_SIZE] {
+        let mut data = [0u8; PAGE_SIZE];
+        data[..size_of::<Header>()].copy_from_slice(self.header.as_bytes());
+        set_checksum(&mut data);
+        data
+    }
+
+    // Logs the header as a group of its own and writes it through.
+    fn write_header(&mut self) -> io::Result<()> {
+        self.header_changed = true;
+        self.log_commit()?;
+        self.sync_log()
+    }
+
+    // A free page is read from the heap file only if its logged image has
+    // been written there.
+    fn take_free_page(&mut self, page_id: PageId) -> io::Result<[u8; PAGE_SIZE]> {
+        for pages in [&mut self.free_pages, &mut self.unwritten].iter_mut() {
+            if let Some(idx) = pages.iter().position(|&(id, _)| id == page_id) {
+                return Ok(pages.swap_remove(idx).1);
+            }
+        }
+        let mut page = [0u8; PAGE_SIZE];
+        self.read_page_data(page_id, &mut page)?;
+        Ok(page)
+    }
+
+    pub fn catalog_page_id(&self) -> Option<PageId> {
+        self.header.catalog_page_id.valid()
     }
 
-    pub fn allocate_page(&mut self) -> PageId {
+    pub fn set_catalog_page_id(&mut self, page_id: PageId) -> io::Result<()> {
+        self.header.catalog_page_id = page_id;
+        self.write_header()
+    }
+
+    pub fn clog_page_id(&self) -> Option<PageId> {
+        self.header.clog_page_id.valid()
+    }
+
+    pub fn set_clog_page_id(&mut self, page_id: PageId) -> io::Result<()> {
+        self.header.clog_page_id = page_id;
+        self.write_header()
+    }
+
+    pub fn next_xid(&self) -> u64 {
+        self.header.next_xid
+    }
+
+    pub fn set_next_xid(&mut self, next_xid: u64) -> io::Result<()> {
+        self.header.next_xid = next_xid;
+        self.write_header()
+    }
+
+    pub fn allocate_page(&mut self) -> Result<PageId, Error> {
+        if let Some(page_id) = self.header.free_page_id.valid() {
+            let free_page = self.take_free_page(page_id)?;
+            // a corrupted link would hand out a page that is still in use
+            if !verify_checksum(&free_page) {
+                return Err(Error::Corruption { page_id });
+            }
+            self.header.free_page_id = PageId::from(&free_page[..size_of::<PageId>()]);
+            self.header_changed = true;
+            return Ok(page_id);
+        }
         let page_id = self.next_page_id;
         self.next_page_id += 1;
-        PageId(page_id)
+        Ok(PageId(page_id))
+    }
+
+    pub fn deallocate_page(&mut self, page_id: PageId) {
+        assert_ne!(page_id, HEADER_PAGE_ID);
+        let mut free_page = [0u8; PAGE_SIZE];
+        free_page[..size_of::<PageId>()].copy_from_slice(self.header.free_page_id.as_bytes());
+        set_checksum(&mut free_page);
+        self.header.free_page_id = page_id;
+        self.header_changed = true;
+        self.free_pages.push((page_id, free_page));
+    }
+
+    // Whether the header or a freed page waits for the next log group.
+    pub fn has_unlogged_changes(&self) -> bool {
+        self.header_changed || !self.free_pages.is_empty()
+    }
+
+    pub fn log_page(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        self.stats.log_pages += 1;
+        self.wal.append_page(page_id, data)
+    }
+
+    // Ends the group, with the header and the pages freed since the last
+    // group as part of it.
+    pub fn log_commit(&mut self) -> io::Result<()> {
+        for (page_id, page) in mem::take(&mut self.free_pages) {
+            self.log_page(page_id, &page)?;
+            self.unwritten.push((page_id, page));
+        }
+        if self.header_changed {
+            let data = self.header_page();
+            self.log_page(HEADER_PAGE_ID, &data)?;
+            self.unwritten
+                .retain(|&(page_id, _)| page_id != HEADER_PAGE_ID);
+            self.unwritten.push((HEADER_PAGE_ID, data));
+            self.header_changed = false;
+        }
+        self.wal.append_commit()
+    }
+
+    pub fn log_lsn(&self) -> Lsn {
+        self.wal.lsn()
+    }
+
+    // The heap file must have been synced with every group before
+    // `redo_lsn`.
+    pub fn log_checkpoint(&mut self, redo_lsn: Lsn) -> io::Result<()> {
+        self.stats.log_syncs += 1;
+        self.wal.checkpoint(redo_lsn)?;
+        // groups logged while the checkpoint ran are still to be written, and
+        // a checkpoint begun before the log was truncated changes nothing
+        self.checkpointed_lsn = self.checkpointed_lsn.max(redo_lsn);
+        Ok(())
+    }
+
+    // Whether anything was logged since the last checkpoint or truncation.
+    pub fn needs_checkpoint(&self) -> bool {
+        self.wal.lsn() > self.checkpointed_lsn
+    }
+
+    // The header and free pages logged so far follow the log to disk, so
+    // that replaying older groups can never roll them back.
+    pub fn sync_log(&mut self) -> io::Result<()> {
+        self.stats.log_syncs += 1;
+        self.wal.sync()?;
+        for (page_id, page) in mem::take(&mut self.unwritten) {
+            self.write_page_data(page_id, &page)?;
+        }
+        Ok(())
+    }
+
+    pub fn truncate_log(&mut self) -> io::Result<()> {
+        self.wal.truncate()?;
+        self.checkpointed_lsn = self.wal.lsn();
+        Ok(())
     }
 
     pub fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
-        self.heap_file.read_exact(data)
+        self.stats.page_reads += 1;
+        self.heap.read_page(page_id, data)
     }
 
     pub fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
-        self.heap_file.seek(SeekFrom::Start(offset))?;
-        self.heap_file.write_all(data)
+        self.stats.page_writes += 1;
+        self.heap.write_page(page_id, data)
+    }
+
+    // Writes whole pages in the given order, with one system call for each
+    // run of consecutive page ids.
+    pub fn write_pages(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        let mut start = 0;
+        while start < pages.len() {
+            let mut end = start + 1;
+            while end < pages.len() && pages[end].0.to_u64() == pages[end - 1].0.to_u64() + 1 {
+                end += 1;
+            }
+            let run: Vec<_> = pages[start..end].iter().map(|&(_, page)| page).collect();
+            self.heap.write_run(pages[start].0, &run)?;
+            self.stats.page_writes += run.len() as u64;
+            self.stats.vectored_writes += 1;
+            start = end;
+        }
+        Ok(())
     }
 
     pub fn sync(&mut self) -> io::Result<()> {
-        self.heap_file.flush()?;
-        self.heap_file.sync_all()
+        self.stats.syncs += 1;
+        self.heap.sync()
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = DiskStats::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_free_page_reuse() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        assert!(page_ids.iter().all(|&page_id| page_id != HEADER_PAGE_ID));
+        for &page_id in &page_ids {
+            disk.write_page_data(page_id, &[0xab; PAGE_SIZE]).unwrap();
+        }
+        disk.reset_stats();
+        disk.deallocate_page(page_ids[0]);
+        disk.deallocate_page(page_ids[2]);
+        // both frees reach the disk with a single log group
+        disk.log_commit().unwrap();
+        disk.sync_log().unwrap();
+        assert_eq!(1, disk.stats().log_syncs);
+        assert_eq!(3, disk.stats().log_pages);
+        disk.sync().unwrap();
+        drop(disk);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[2], disk.allocate_page().unwrap());
+        assert_eq!(page_ids[0], disk.allocate_page().unwrap());
+        assert_eq!(
+            PageId(page_ids[2].to_u64() + 1),
+            disk.allocate_page().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_corrupted_free_page() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.deallocate_page(page_id);
+        disk.log_commit().unwrap();
+        disk.sync_log().unwrap();
+        disk.write_page_data(page_id, &[0xab; PAGE_SIZE]).unwrap();
+        disk.sync().unwrap();
+        disk.truncate_log().unwrap();
+        drop(disk);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert!(matches!(
+            disk.allocate_page(),
+            Err(Error::Corruption { page_id: corrupted }) if corrupted == page_id
+        ));
+    }
+
+    #[test]
+    fn test_header_validation() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(None, disk.catalog_page_id());
+        let catalog_page_id = disk.allocate_page().unwrap();
+        disk.set_catalog_page_id(catalog_page_id).unwrap();
+        drop(disk);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(Some(catalog_page_id), disk.catalog_page_id());
+
+        let mut header = [0u8; size_of::<Header>()];
+        disk.read_page_data(HEADER_PAGE_ID, &mut header).unwrap();
+        header[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_ne_bytes());
+        disk.write_page_data(HEADER_PAGE_ID, &header).unwrap();
+        drop(disk);
+        assert!(matches!(
+            DiskManager::open(&data_file_path),
+            Err(Error::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1
+        ));
+
+        let mut garbage = tempfile::tempfile().unwrap();
+        garbage.write_all(&[0xde; PAGE_SIZE]).unwrap();
+        assert!(matches!(
+            DiskManager::new(garbage, tempfile::tempfile().unwrap()),
+            Err(Error::InvalidMagic)
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_direct_io() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let options = DiskOptions { direct_io: true };
+        let mut disk = match DiskManager::open_with_options(&data_file_path, options) {
+            Ok(disk) => disk,
+            // the file system does not support direct I/O
+            Err(Error::Io(err)) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("{}", err),
+        };
+        let page_ids: Vec<_> = (0..5).map(|_| disk.allocate_page().unwrap()).collect();
+        let pages: Vec<_> = (0..5u8).map(|i| [i; PAGE_SIZE]).collect();
+        let mut writes: Vec<_> = page_ids
+            .iter()
+            .zip(&pages)
+            .map(|(&page_id, page)| (page_id, &page[..]))
+            .collect();
+        writes.remove(3);
+        disk.reset_stats();
+        disk.write_pages(&writes).unwrap();
+        assert_eq!(4, disk.stats().page_writes);
+        assert_eq!(2, disk.stats().vectored_writes);
+        disk.write_page_data(page_ids[3], &pages[3]).unwrap();
+        disk.write_page_data(page_ids[4], &[9; 8]).unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let mut page = [0u8; PAGE_SIZE];
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            disk.read_page_data(page_id, &mut page).unwrap();
+            let expected = if i == 4 { 9 } else { i as u8 };
+            assert_eq!(expected, page[0]);
+            assert_eq!(i as u8, page[PAGE_SIZE - 1]);
+        }
     }
 }
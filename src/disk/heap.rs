@@ -0,0 +1,190 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+
+use super::{PageId, PAGE_SIZE};
+
+// Direct I/O needs buffers aligned to the device's logical block size, which
+// is never larger than a page on the systems we run on.
+#[repr(C, align(4096))]
+struct AlignedPage([u8; PAGE_SIZE]);
+
+impl AlignedPage {
+    fn new() -> Self {
+        Self([0u8; PAGE_SIZE])
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_direct_io(options: &mut OpenOptions) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    options.custom_flags(libc::O_DIRECT);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_direct_io(_options: &mut OpenOptions) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on Linux",
+    ))
+}
+
+// pwritev takes at most IOV_MAX buffers, which is 1024 on Linux.
+const MAX_IOVECS: usize = 1024;
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    file.write_all_at(buf, offset)
+}
+
+// Elsewhere the file is positioned first, which is safe since the disk
+// manager's lock serializes every access to it.
+#[cfg(not(unix))]
+fn read_exact_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(mut file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+// The heap file, read and written at page offsets. With direct I/O the OS
+// page cache is bypassed, so every transfer goes through aligned buffers and
+// covers whole pages.
+pub struct HeapFile {
+    file: File,
+    direct_io: bool,
+}
+
+impl HeapFile {
+    pub fn new(file: File, direct_io: bool) -> Self {
+        Self { file, direct_io }
+    }
+
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn offset(page_id: PageId) -> u64 {
+        PAGE_SIZE as u64 * page_id.to_u64()
+    }
+
+    pub fn read_page(&self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        if !self.direct_io {
+            return read_exact_at(&self.file, data, Self::offset(page_id));
+        }
+        let mut aligned = AlignedPage::new();
+        read_exact_at(&self.file, &mut aligned.0, Self::offset(page_id))?;
+        data.copy_from_slice(&aligned.0[..data.len()]);
+        Ok(())
+    }
+
+    pub fn write_page(&self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        if !self.direct_io {
+            return write_all_at(&self.file, data, Self::offset(page_id));
+        }
+        let mut aligned = AlignedPage::new();
+        if data.len() < PAGE_SIZE {
+            // the rest of the page is kept, or zero past the end of the file
+            match read_exact_at(&self.file, &mut aligned.0, Self::offset(page_id)) {
+                Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err),
+                _ => {}
+            }
+        }
+        aligned.0[..data.len()].copy_from_slice(data);
+        write_all_at(&self.file, &aligned.0, Self::offset(page_id))
+    }
+
+    // Writes whole pages with consecutive ids starting at `page_id`, with one
+    // system call for up to `MAX_IOVECS` pages.
+    pub fn write_run(&self, page_id: PageId, pages: &[&[u8]]) -> io::Result<()> {
+        let mut first_page_id = page_id.to_u64();
+        for chunk in pages.chunks(MAX_IOVECS) {
+            if self.direct_io {
+                let aligned: Vec<_> = chunk
+                    .iter()
+                    .map(|page| {
+                        let mut aligned = AlignedPage::new();
+                        aligned.0.copy_from_slice(page);
+                        aligned
+                    })
+                    .collect();
+                let pages: Vec<&[u8]> = aligned.iter().map(|page| &page.0[..]).collect();
+                self.write_chunk(PageId(first_page_id), &pages)?;
+            } else {
+                self.write_chunk(PageId(first_page_id), chunk)?;
+            }
+            first_page_id += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn write_chunk(&self, page_id: PageId, pages: &[&[u8]]) -> io::Result<()> {
+        let iovecs: Vec<_> = pages
+            .iter()
+            .map(|page| {
+                assert_eq!(PAGE_SIZE, page.len());
+                libc::iovec {
+                    iov_base: page.as_ptr() as *mut libc::c_void,
+                    iov_len: page.len(),
+                }
+            })
+            .collect();
+        let written = loop {
+            // SAFETY: every iovec points into a page borrowed for the whole
+            // call, and the file descriptor stays open while `self` lives
+            let written = unsafe {
+                libc::pwritev(
+                    self.file.as_raw_fd(),
+                    iovecs.as_ptr(),
+                    iovecs.len() as libc::c_int,
+                    Self::offset(page_id) as libc::off_t,
+                )
+            };
+            if written >= 0 {
+                break written as usize;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        };
+        // a short write is finished page by page
+        for (idx, page) in pages.iter().enumerate().skip(written / PAGE_SIZE) {
+            self.write_page(PageId(page_id.to_u64() + idx as u64), page)?;
+        }
+        Ok(())
+    }
+
+    // Without pwritev the pages go out one write at a time.
+    #[cfg(not(unix))]
+    fn write_chunk(&self, page_id: PageId, pages: &[&[u8]]) -> io::Result<()> {
+        for (idx, page) in pages.iter().enumerate() {
+            assert_eq!(PAGE_SIZE, page.len());
+            self.write_page(PageId(page_id.to_u64() + idx as u64), page)?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
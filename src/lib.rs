@@ -2,8 +2,12 @@ mod bsearch;
 pub mod btree;
 pub mod buffer;
 pub mod disk;
+pub mod lock;
 mod memcmpable;
+pub mod mvcc;
 pub mod query;
 mod slotted;
 pub mod table;
+pub mod transaction;
 pub mod tuple;
+pub mod wal;
@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, Mutex};
+
+use thiserror::Error;
+
+use crate::disk::PageId;
+use crate::mvcc::Xid;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("transaction {0} was aborted to resolve a deadlock")]
+    Deadlock(Xid),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn conflicts_with(self, other: LockMode) -> bool {
+        self == LockMode::Exclusive || other == LockMode::Exclusive
+    }
+}
+
+// A row, or a unique index entry, is identified by the meta page of its tree
+// and its encoded key.
+pub type LockKey = (PageId, Vec<u8>);
+
+#[derive(Debug, Default)]
+struct State {
+    holders: HashMap<LockKey, HashMap<Xid, LockMode>>,
+    held: HashMap<Xid, Vec<LockKey>>,
+    waiting: HashMap<Xid, (LockKey, LockMode)>,
+    victims: HashSet<Xid>,
+}
+
+impl State {
+    fn blockers(&self, xid: Xid, key: &LockKey, mode: LockMode) -> Vec<Xid> {
+        self.holders
+            .get(key)
+            .map(|holders| {
+                holders
+                    .iter()
+                    .filter(|&(&holder, &held)| holder != xid && held.conflicts_with(mode))
+                    .map(|(&holder, _)| holder)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // Returns the transactions on a cycle of the wait-for graph through
+    // `xid`, if there is one.
+    fn find_cycle(&self, xid: Xid) -> Option<Vec<Xid>> {
+        let mut path = vec![xid];
+        let mut visited = HashSet::new();
+        self.find_path(xid, xid, &mut path, &mut visited)
+    }
+
+    fn find_path(
+        &self,
+        target: Xid,
+        xid: Xid,
+        path: &mut Vec<Xid>,
+        visited: &mut HashSet<Xid>,
+    ) -> Option<Vec<Xid>> {
+        let (key, mode) = self.waiting.get(&xid)?;
+        for blocker in self.blockers(xid, key, *mode) {
+            if blocker == target {
+                return Some(path.clone());
+            }
+            if visited.insert(blocker) {
+                path.push(blocker);
+                if let Some(cycle) = self.find_path(target, blocker, path, visited) {
+                    return Some(cycle);
+                }
+                path.pop();
+            }
+        }
+        None
+    }
+
+    fn grant(&mut self, xid: Xid, key: LockKey, mode: LockMode) {
+        let holders = self.holders.entry(key.clone()).or_default();
+        match holders.insert(xid, mode) {
+            Some(LockMode::Exclusive) => {
+                holders.insert(xid, LockMode::Exclusive);
+            }
+            Some(LockMode::Shared) => {}
+            None => self.held.entry(xid).or_default().push(key),
+        }
+    }
+}
+
+// Row locks for two-phase locking: a transaction acquires locks as it goes
+// and releases all of them when it ends.
+#[derive(Debug, Default)]
+pub struct LockManager {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Blocks until the lock is granted. A request that closes a cycle of
+    // waiting transactions aborts the youngest one on it, which may be the
+    // requester itself; the victim's pending request fails with `Deadlock`.
+    pub fn lock(&self, xid: Xid, key: LockKey, mode: LockMode) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.victims.remove(&xid) {
+                state.waiting.remove(&xid);
+                return Err(Error::Deadlock(xid));
+            }
+            if state.blockers(xid, &key, mode).is_empty() {
+                state.waiting.remove(&xid);
+                state.grant(xid, key, mode);
+                return Ok(());
+            }
+            state.waiting.insert(xid, (key.clone(), mode));
+            if let Some(cycle) = state.find_cycle(xid) {
+                let victim = cycle.into_iter().max().unwrap();
+                if victim == xid {
+                    state.waiting.remove(&xid);
+                    return Err(Error::Deadlock(xid));
+                }
+                state.victims.insert(victim);
+                self.changed.notify_all();
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    pub fn release_all(&self, xid: Xid) {
+        let mut state = self.state.lock().unwrap();
+        for key in state.held.remove(&xid).unwrap_or_default() {
+            if let Some(holders) = state.holders.get_mut(&key) {
+                holders.remove(&xid);
+                if holders.is_empty() {
+                    state.holders.remove(&key);
+                }
+            }
+        }
+        state.waiting.remove(&xid);
+        state.victims.remove(&xid);
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    fn key(k: &[u8]) -> LockKey {
+        (PageId(1), k.to_vec())
+    }
+
+    #[test]
+    fn test_lock_modes() {
+        let locks = Arc::new(LockManager::new());
+        locks.lock(2, key(b"a"), LockMode::Shared).unwrap();
+        locks.lock(3, key(b"a"), LockMode::Shared).unwrap();
+        locks.lock(3, key(b"b"), LockMode::Exclusive).unwrap();
+        locks.lock(3, key(b"b"), LockMode::Shared).unwrap();
+
+        let waiter = {
+            let locks = Arc::clone(&locks);
+            thread::spawn(move || locks.lock(2, key(b"a"), LockMode::Exclusive))
+        };
+        locks.release_all(3);
+        waiter.join().unwrap().unwrap();
+        locks.lock(4, key(b"b"), LockMode::Exclusive).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let locks = Arc::new(LockManager::new());
+        locks.lock(2, key(b"a"), LockMode::Exclusive).unwrap();
+        locks.lock(3, key(b"b"), LockMode::Exclusive).unwrap();
+        let younger = {
+            let locks = Arc::clone(&locks);
+            thread::spawn(move || {
+                let result = locks.lock(3, key(b"a"), LockMode::Shared);
+                locks.release_all(3);
+                result
+            })
+        };
+        // whichever request closes the cycle, the younger transaction loses
+        locks.lock(2, key(b"b"), LockMode::Exclusive).unwrap();
+        assert!(matches!(younger.join().unwrap(), Err(Error::Deadlock(3))));
+        locks.release_all(2);
+    }
+}
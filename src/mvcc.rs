@@ -0,0 +1,184 @@
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+pub type Xid = u64;
+
+pub const INVALID_XID: Xid = 0;
+pub const FROZEN_XID: Xid = 1;
+pub const FIRST_NORMAL_XID: Xid = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub xmin: Xid,
+    pub xmax: Xid,
+    pub value: Vec<u8>,
+}
+
+// A versioned value is the chain of versions of one key, newest first.
+pub fn encode(versions: &[Version]) -> Vec<u8> {
+    bincode::options().serialize(versions).unwrap()
+}
+
+pub fn decode(bytes: &[u8]) -> Vec<Version> {
+    bincode::options().deserialize(bytes).unwrap()
+}
+
+pub fn frozen(value: &[u8]) -> Vec<u8> {
+    encode(&[Version {
+        xmin: FROZEN_XID,
+        xmax: INVALID_XID,
+        value: value.to_vec(),
+    }])
+}
+
+// The commit log is stored in chunks of this many xids.
+pub const XIDS_PER_CHUNK: u64 = 4096;
+
+// Which transactions have committed, one bit per xid. A transaction that is
+// over without having committed, because it rolled back, was dropped or was
+// cut short by a crash, is aborted, and none of its versions is visible.
+#[derive(Default)]
+pub struct CommitLog {
+    bits: RwLock<Vec<u8>>,
+}
+
+impl CommitLog {
+    pub fn is_committed(&self, xid: Xid) -> bool {
+        if xid == FROZEN_XID {
+            return true;
+        }
+        let bits = self.bits.read().unwrap();
+        bits.get((xid / 8) as usize)
+            .is_some_and(|byte| byte & (1 << (xid % 8)) != 0)
+    }
+
+    pub fn set_committed(&self, xid: Xid) {
+        let mut bits = self.bits.write().unwrap();
+        let idx = (xid / 8) as usize;
+        if bits.len() <= idx {
+            bits.resize(idx + 1, 0);
+        }
+        bits[idx] |= 1 << (xid % 8);
+    }
+
+    // The chunk holding `xid` as it is stored once `xid` has committed,
+    // along with the chunk's number.
+    pub fn committed_chunk(&self, xid: Xid) -> (u64, Vec<u8>) {
+        let chunk_id = xid / XIDS_PER_CHUNK;
+        let chunk_size = (XIDS_PER_CHUNK / 8) as usize;
+        let start = chunk_id as usize * chunk_size;
+        let mut chunk = vec![0; chunk_size];
+        {
+            let bits = self.bits.read().unwrap();
+            if let Some(stored) = bits.get(start..) {
+                let len = stored.len().min(chunk_size);
+                chunk[..len].copy_from_slice(&stored[..len]);
+            }
+        }
+        let offset = xid % XIDS_PER_CHUNK;
+        chunk[(offset / 8) as usize] |= 1 << (offset % 8);
+        (chunk_id, chunk)
+    }
+
+    pub fn load_chunk(&self, chunk_id: u64, chunk: &[u8]) {
+        let mut bits = self.bits.write().unwrap();
+        let start = chunk_id as usize * chunk.len();
+        if bits.len() < start + chunk.len() {
+            bits.resize(start + chunk.len(), 0);
+        }
+        bits[start..start + chunk.len()].copy_from_slice(chunk);
+    }
+}
+
+impl fmt::Debug for CommitLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitLog").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub xid: Xid,
+    pub xmax: Xid,
+    pub active: Vec<Xid>,
+    pub commits: Arc<CommitLog>,
+}
+
+impl Snapshot {
+    // Whether the effects of `xid` are visible: it is our own transaction or
+    // it had committed by the time the snapshot was taken.
+    pub fn sees(&self, xid: Xid) -> bool {
+        xid == self.xid
+            || (xid < self.xmax && !self.active.contains(&xid) && self.commits.is_committed(xid))
+    }
+
+    pub fn is_visible(&self, version: &Version) -> bool {
+        self.sees(version.xmin) && !(version.xmax != INVALID_XID && self.sees(version.xmax))
+    }
+
+    pub fn visible_value(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        decode(bytes)
+            .into_iter()
+            .find(|version| self.is_visible(version))
+            .map(|version| version.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_visibility() {
+        let commits = CommitLog::default();
+        for &xid in &[2, 3, 4, 8] {
+            commits.set_committed(xid);
+        }
+        let snapshot = Snapshot {
+            xid: 5,
+            xmax: 7,
+            active: vec![3],
+            commits: Arc::new(commits),
+        };
+        let version = |xmin, xmax| Version {
+            xmin,
+            xmax,
+            value: vec![],
+        };
+        assert!(snapshot.is_visible(&version(FROZEN_XID, INVALID_XID)));
+        assert!(snapshot.is_visible(&version(2, INVALID_XID)));
+        assert!(snapshot.is_visible(&version(5, INVALID_XID)));
+        assert!(!snapshot.is_visible(&version(3, INVALID_XID)));
+        assert!(!snapshot.is_visible(&version(7, INVALID_XID)));
+        assert!(!snapshot.is_visible(&version(2, 4)));
+        assert!(!snapshot.is_visible(&version(2, 5)));
+        assert!(snapshot.is_visible(&version(2, 3)));
+        assert!(snapshot.is_visible(&version(2, 8)));
+        // 6 is over but never committed
+        assert!(!snapshot.is_visible(&version(6, INVALID_XID)));
+        assert!(snapshot.is_visible(&version(2, 6)));
+
+        let bytes = encode(&[version(8, INVALID_XID), version(2, 8)]);
+        assert_eq!(Some(vec![]), snapshot.visible_value(&bytes));
+        assert_eq!(None, snapshot.visible_value(&encode(&[version(8, 9)])));
+    }
+
+    #[test]
+    fn test_commit_log_chunks() {
+        let commits = CommitLog::default();
+        commits.set_committed(XIDS_PER_CHUNK + 1);
+        let (chunk_id, chunk) = commits.committed_chunk(XIDS_PER_CHUNK + 9);
+        assert_eq!(1, chunk_id);
+
+        let loaded = CommitLog::default();
+        loaded.load_chunk(chunk_id, &chunk);
+        assert!(loaded.is_committed(XIDS_PER_CHUNK + 1));
+        assert!(loaded.is_committed(XIDS_PER_CHUNK + 9));
+        assert!(!loaded.is_committed(XIDS_PER_CHUNK + 2));
+        assert!(!loaded.is_committed(9));
+        assert!(loaded.is_committed(FROZEN_XID));
+    }
+}
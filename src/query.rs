@@ -1,8 +1,12 @@
+use std::ops::Bound;
+
 use anyhow::Result;
 
-use crate::btree::{self, BTree, SearchMode};
+use crate::btree::{self, BTree, Direction, SearchMode};
 use crate::buffer::BufferPoolManager;
 use crate::disk::PageId;
+use crate::lock::LockMode;
+use crate::transaction::Transaction;
 use crate::tuple;
 
 pub type Tuple = Vec<Vec<u8>>;
@@ -10,65 +14,103 @@ pub type TupleSlice<'a> = &'a [Vec<u8>];
 
 pub enum TupleSearchMode<'a> {
     Start,
+    End,
     Key(&'a [&'a [u8]]),
+    Range {
+        start: Bound<&'a [&'a [u8]]>,
+        end: Bound<&'a [&'a [u8]]>,
+    },
 }
 
 impl<'a> TupleSearchMode<'a> {
     fn encode(&self) -> SearchMode {
         match self {
             TupleSearchMode::Start => SearchMode::Start,
+            TupleSearchMode::End => SearchMode::End,
             TupleSearchMode::Key(tuple) => {
                 let mut key = vec![];
                 tuple::encode(tuple.iter(), &mut key);
                 SearchMode::Key(key)
             }
+            TupleSearchMode::Range { start, end } => SearchMode::Range {
+                start: encode_bound(start),
+                end: encode_bound(end),
+            },
         }
     }
 }
 
+fn encode_bound(bound: &Bound<&[&[u8]]>) -> Bound<Vec<u8>> {
+    let encode = |tuple: &[&[u8]]| {
+        let mut key = vec![];
+        tuple::encode(tuple.iter(), &mut key);
+        key
+    };
+    match bound {
+        Bound::Included(tuple) => Bound::Included(encode(tuple)),
+        Bound::Excluded(tuple) => Bound::Excluded(encode(tuple)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
 pub trait Executor {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>>;
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>>;
 }
 
 pub type BoxExecutor<'a> = Box<dyn Executor + 'a>;
 
 pub trait PlanNode {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor>;
+    fn start(&self, bufmgr: &BufferPoolManager) -> Result<BoxExecutor<'_>>;
 }
 
 pub struct SeqScan<'a> {
     pub table_meta_page_id: PageId,
     pub search_mode: TupleSearchMode<'a>,
+    pub direction: Direction,
+    pub txn: &'a Transaction,
     pub while_cond: &'a dyn Fn(TupleSlice) -> bool,
 }
 
 impl<'a> PlanNode for SeqScan<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+    fn start(&self, bufmgr: &BufferPoolManager) -> Result<BoxExecutor<'_>> {
         let btree = BTree::new(self.table_meta_page_id);
-        let table_iter = btree.search(bufmgr, self.search_mode.encode())?;
+        let table_iter =
+            btree.search_with_direction(bufmgr, self.search_mode.encode(), self.direction)?;
         Ok(Box::new(ExecSeqScan {
+            table_meta_page_id: self.table_meta_page_id,
             table_iter,
+            direction: self.direction,
+            txn: self.txn,
             while_cond: self.while_cond,
         }))
     }
 }
 
 pub struct ExecSeqScan<'a> {
+    table_meta_page_id: PageId,
     table_iter: btree::Iter,
+    direction: Direction,
+    txn: &'a Transaction,
     while_cond: &'a dyn Fn(TupleSlice) -> bool,
 }
 
 impl<'a> Executor for ExecSeqScan<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
-        let (pkey_bytes, tuple_bytes) = match self.table_iter.next(bufmgr)? {
-            Some(pair) => pair,
-            None => return Ok(None),
-        };
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        let (pkey_bytes, tuple_bytes) =
+            match self
+                .table_iter
+                .advance_visible(bufmgr, self.direction, self.txn.snapshot())?
+            {
+                Some(pair) => pair,
+                None => return Ok(None),
+            };
         let mut pkey = vec![];
         tuple::decode(&pkey_bytes, &mut pkey);
         if !(self.while_cond)(&pkey) {
             return Ok(None);
         }
+        self.txn
+            .lock(self.table_meta_page_id, &pkey_bytes, LockMode::Shared)?;
         let mut tuple = pkey;
         tuple::decode(&tuple_bytes, &mut tuple);
         Ok(Some(tuple))
@@ -81,7 +123,7 @@ pub struct Filter<'a> {
 }
 
 impl<'a> PlanNode for Filter<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+    fn start(&self, bufmgr: &BufferPoolManager) -> Result<BoxExecutor<'_>> {
         let inner_iter = self.inner_plan.start(bufmgr)?;
         Ok(Box::new(ExecFilter {
             inner_iter,
@@ -96,7 +138,7 @@ pub struct ExecFilter<'a> {
 }
 
 impl<'a> Executor for ExecFilter<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
         loop {
             match self.inner_iter.next(bufmgr)? {
                 Some(tuple) => {
@@ -114,17 +156,22 @@ pub struct IndexScan<'a> {
     pub table_meta_page_id: PageId,
     pub index_meta_page_id: PageId,
     pub search_mode: TupleSearchMode<'a>,
+    pub direction: Direction,
+    pub txn: &'a Transaction,
     pub while_cond: &'a dyn Fn(TupleSlice) -> bool,
 }
 
 impl<'a> PlanNode for IndexScan<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+    fn start(&self, bufmgr: &BufferPoolManager) -> Result<BoxExecutor<'_>> {
         let table_btree = BTree::new(self.table_meta_page_id);
         let index_btree = BTree::new(self.index_meta_page_id);
-        let index_iter = index_btree.search(bufmgr, self.search_mode.encode())?;
+        let index_iter =
+            index_btree.search_with_direction(bufmgr, self.search_mode.encode(), self.direction)?;
         Ok(Box::new(ExecIndexScan {
             table_btree,
             index_iter,
+            direction: self.direction,
+            txn: self.txn,
             while_cond: self.while_cond,
         }))
     }
@@ -133,64 +180,96 @@ impl<'a> PlanNode for IndexScan<'a> {
 pub struct ExecIndexScan<'a> {
     table_btree: BTree,
     index_iter: btree::Iter,
+    direction: Direction,
+    txn: &'a Transaction,
     while_cond: &'a dyn Fn(TupleSlice) -> bool,
 }
 
 impl<'a> Executor for ExecIndexScan<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
-        let (skey_bytes, pkey_bytes) = match self.index_iter.next(bufmgr)? {
-            Some(pair) => pair,
-            None => return Ok(None),
-        };
-        let mut skey = vec![];
-        tuple::decode(&skey_bytes, &mut skey);
-        if !(self.while_cond)(&skey) {
-            return Ok(None);
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        loop {
+            let (skey_bytes, pkey_bytes) = match self.index_iter.advance_visible(
+                bufmgr,
+                self.direction,
+                self.txn.snapshot(),
+            )? {
+                Some(pair) => pair,
+                None => return Ok(None),
+            };
+            let mut skey = vec![];
+            tuple::decode(&skey_bytes, &mut skey);
+            if !(self.while_cond)(&skey) {
+                return Ok(None);
+            }
+            self.txn
+                .lock(self.table_btree.meta_page_id, &pkey_bytes, LockMode::Shared)?;
+            // index entries and rows are versioned apart, so an entry may
+            // outlive the row version it was made for
+            let tuple_bytes = match self
+                .table_btree
+                .get(bufmgr, &pkey_bytes)?
+                .and_then(|bytes| self.txn.snapshot().visible_value(&bytes))
+            {
+                Some(tuple_bytes) => tuple_bytes,
+                None => continue,
+            };
+            let mut tuple = vec![];
+            tuple::decode(&pkey_bytes, &mut tuple);
+            tuple::decode(&tuple_bytes, &mut tuple);
+            return Ok(Some(tuple));
         }
-        let mut table_iter = self
-            .table_btree
-            .search(bufmgr, SearchMode::Key(pkey_bytes))?;
-        let (pkey_bytes, tuple_bytes) = table_iter.next(bufmgr)?.unwrap();
-        let mut tuple = vec![];
-        tuple::decode(&pkey_bytes, &mut tuple);
-        tuple::decode(&tuple_bytes, &mut tuple);
-        Ok(Some(tuple))
     }
 }
 
 pub struct IndexOnlyScan<'a> {
+    pub table_meta_page_id: PageId,
     pub index_meta_page_id: PageId,
     pub search_mode: TupleSearchMode<'a>,
+    pub direction: Direction,
+    pub txn: &'a Transaction,
     pub while_cond: &'a dyn Fn(TupleSlice) -> bool,
 }
 
 impl<'a> PlanNode for IndexOnlyScan<'a> {
-    fn start(&self, bufmgr: &mut BufferPoolManager) -> Result<BoxExecutor> {
+    fn start(&self, bufmgr: &BufferPoolManager) -> Result<BoxExecutor<'_>> {
         let btree = BTree::new(self.index_meta_page_id);
-        let index_iter = btree.search(bufmgr, self.search_mode.encode())?;
+        let index_iter =
+            btree.search_with_direction(bufmgr, self.search_mode.encode(), self.direction)?;
         Ok(Box::new(ExecIndexOnlyScan {
+            table_meta_page_id: self.table_meta_page_id,
             index_iter,
+            direction: self.direction,
+            txn: self.txn,
             while_cond: self.while_cond,
         }))
     }
 }
 
 pub struct ExecIndexOnlyScan<'a> {
+    table_meta_page_id: PageId,
     index_iter: btree::Iter,
+    direction: Direction,
+    txn: &'a Transaction,
     while_cond: &'a dyn Fn(TupleSlice) -> bool,
 }
 
 impl<'a> Executor for ExecIndexOnlyScan<'a> {
-    fn next(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<Tuple>> {
-        let (skey_bytes, pkey_bytes) = match self.index_iter.next(bufmgr)? {
-            Some(pair) => pair,
-            None => return Ok(None),
-        };
+    fn next(&mut self, bufmgr: &BufferPoolManager) -> Result<Option<Tuple>> {
+        let (skey_bytes, pkey_bytes) =
+            match self
+                .index_iter
+                .advance_visible(bufmgr, self.direction, self.txn.snapshot())?
+            {
+                Some(pair) => pair,
+                None => return Ok(None),
+            };
         let mut skey = vec![];
         tuple::decode(&skey_bytes, &mut skey);
         if !(self.while_cond)(&skey) {
             return Ok(None);
         }
+        self.txn
+            .lock(self.table_meta_page_id, &pkey_bytes, LockMode::Shared)?;
         let mut tuple = skey;
         tuple::decode(&pkey_bytes, &mut tuple);
         Ok(Some(tuple))
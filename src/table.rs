@@ -1,8 +1,11 @@
 use anyhow::Result;
 
-use crate::btree::BTree;
+use crate::btree::{self, BTree, SearchMode};
 use crate::buffer::BufferPoolManager;
 use crate::disk::PageId;
+use crate::lock::LockMode;
+use crate::mvcc;
+use crate::transaction::Transaction;
 use crate::tuple;
 
 #[derive(Debug)]
@@ -12,13 +15,13 @@ pub struct SimpleTable {
 }
 
 impl SimpleTable {
-    pub fn create(&mut self, bufmgr: &mut BufferPoolManager) -> Result<()> {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
         let btree = BTree::create(bufmgr)?;
         self.meta_page_id = btree.meta_page_id;
         Ok(())
     }
 
-    pub fn insert(&self, bufmgr: &mut BufferPoolManager, record: &[&[u8]]) -> Result<()> {
+    pub fn insert(&self, bufmgr: &BufferPoolManager, record: &[&[u8]]) -> Result<()> {
         let btree = BTree::new(self.meta_page_id);
         let mut key = vec![];
         tuple::encode(record[..self.num_key_elems].iter(), &mut key);
@@ -37,7 +40,7 @@ pub struct Table {
 }
 
 impl Table {
-    pub fn create(&mut self, bufmgr: &mut BufferPoolManager) -> Result<()> {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
         let btree = BTree::create(bufmgr)?;
         self.meta_page_id = btree.meta_page_id;
         for unique_index in &mut self.unique_indices {
@@ -46,16 +49,92 @@ impl Table {
         Ok(())
     }
 
-    pub fn insert(&self, bufmgr: &mut BufferPoolManager, record: &[&[u8]]) -> Result<()> {
+    // Loads records into the trees made by `create`, which must still be
+    // empty.
+    pub fn bulk_load(
+        &self,
+        bufmgr: &BufferPoolManager,
+        records: &[&[&[u8]]],
+        fill_factor: u8,
+    ) -> Result<()> {
+        let mut pairs: Vec<_> = records
+            .iter()
+            .map(|record| {
+                let mut key = vec![];
+                tuple::encode(record[..self.num_key_elems].iter(), &mut key);
+                let mut value = vec![];
+                tuple::encode(record[self.num_key_elems..].iter(), &mut value);
+                (key, mvcc::frozen(&value))
+            })
+            .collect();
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        BTree::new(self.meta_page_id).load(bufmgr, pairs, fill_factor)?;
+        for unique_index in &self.unique_indices {
+            unique_index.build(bufmgr, self.meta_page_id, fill_factor)?;
+        }
+        Ok(())
+    }
+
+    pub fn insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &mut Transaction,
+        record: &[&[u8]],
+    ) -> Result<()> {
         let btree = BTree::new(self.meta_page_id);
         let mut key = vec![];
         tuple::encode(record[..self.num_key_elems].iter(), &mut key);
         let mut value = vec![];
         tuple::encode(record[self.num_key_elems..].iter(), &mut value);
-        btree.insert(bufmgr, &key, &value)?;
+        // check every unique key up front, so that a conflict is reported
+        // before anything is written
+        txn.check_insert(bufmgr, &btree, &key)?;
+        for unique_index in &self.unique_indices {
+            unique_index.check_insert(bufmgr, txn, record)?;
+        }
+        let savepoint = txn.savepoint();
+        if let Err(err) = self.insert_entries(bufmgr, txn, &key, &value, record) {
+            txn.rollback_to(bufmgr, savepoint)?;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn insert_entries(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &mut Transaction,
+        key: &[u8],
+        value: &[u8],
+        record: &[&[u8]],
+    ) -> Result<()> {
+        txn.insert(bufmgr, &BTree::new(self.meta_page_id), key, value)?;
+        for unique_index in &self.unique_indices {
+            unique_index.insert(bufmgr, txn, key, record)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &mut Transaction,
+        pkey: &[&[u8]],
+    ) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let mut key = vec![];
+        tuple::encode(pkey.iter(), &mut key);
+        txn.lock(self.meta_page_id, &key, LockMode::Exclusive)?;
+        let value = txn
+            .get(bufmgr, &btree, &key)?
+            .ok_or(btree::Error::KeyNotFound)?;
+        let mut record = vec![];
+        tuple::decode(&key, &mut record);
+        tuple::decode(&value, &mut record);
         for unique_index in &self.unique_indices {
-            unique_index.insert(bufmgr, &key, record)?;
+            unique_index.delete(bufmgr, txn, &record)?;
         }
+        txn.delete(bufmgr, &btree, &key)?;
         Ok(())
     }
 }
@@ -67,25 +146,448 @@ pub struct UniqueIndex {
 }
 
 impl UniqueIndex {
-    pub fn create(&mut self, bufmgr: &mut BufferPoolManager) -> Result<()> {
+    pub fn create(&mut self, bufmgr: &BufferPoolManager) -> Result<()> {
         let btree = BTree::create(bufmgr)?;
         self.meta_page_id = btree.meta_page_id;
         Ok(())
     }
 
-    pub fn insert(
+    pub fn build(
         &self,
-        bufmgr: &mut BufferPoolManager,
-        pkey: &[u8],
-        record: &[impl AsRef<[u8]>],
+        bufmgr: &BufferPoolManager,
+        table_meta_page_id: PageId,
+        fill_factor: u8,
     ) -> Result<()> {
-        let btree = BTree::new(self.meta_page_id);
+        let table_btree = BTree::new(table_meta_page_id);
+        let mut table_iter = table_btree.search(bufmgr, SearchMode::Start)?;
+        let mut pairs = vec![];
+        while let Some((pkey, bytes)) = table_iter.next(bufmgr)? {
+            let head = mvcc::decode(&bytes).swap_remove(0);
+            if head.xmax != mvcc::INVALID_XID {
+                continue;
+            }
+            let mut record = vec![];
+            tuple::decode(&pkey, &mut record);
+            tuple::decode(&head.value, &mut record);
+            pairs.push((self.encode_skey(&record), mvcc::frozen(&pkey)));
+        }
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        BTree::new(self.meta_page_id).load(bufmgr, pairs, fill_factor)?;
+        Ok(())
+    }
+
+    fn encode_skey(&self, record: &[impl AsRef<[u8]>]) -> Vec<u8> {
         let mut skey = vec![];
         tuple::encode(
             self.skey.iter().map(|&index| record[index].as_ref()),
             &mut skey,
         );
-        btree.insert(bufmgr, &skey, pkey)?;
+        skey
+    }
+
+    pub fn check_insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &Transaction,
+        record: &[impl AsRef<[u8]>],
+    ) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        txn.check_insert(bufmgr, &btree, &self.encode_skey(record))?;
         Ok(())
     }
+
+    pub fn insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &mut Transaction,
+        pkey: &[u8],
+        record: &[impl AsRef<[u8]>],
+    ) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        txn.insert(bufmgr, &btree, &self.encode_skey(record), pkey)?;
+        Ok(())
+    }
+
+    pub fn delete(
+        &self,
+        bufmgr: &BufferPoolManager,
+        txn: &mut Transaction,
+        record: &[impl AsRef<[u8]>],
+    ) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        txn.delete(bufmgr, &btree, &self.encode_skey(record))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempfile::{tempdir, tempfile};
+
+    use super::*;
+    use crate::btree::{self, Direction};
+    use crate::buffer::BufferPool;
+    use crate::disk::DiskManager;
+    use crate::lock;
+    use crate::mvcc::Snapshot;
+    use crate::transaction::{self, TransactionManager};
+
+    fn setup() -> (BufferPoolManager, Arc<TransactionManager>, Table) {
+        let disk = DiskManager::new(tempfile().unwrap(), tempfile().unwrap()).unwrap();
+        let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let txn_mgr = TransactionManager::open(&bufmgr).unwrap();
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![UniqueIndex {
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                skey: vec![2],
+            }],
+        };
+        table.create(&bufmgr).unwrap();
+        (bufmgr, txn_mgr, table)
+    }
+
+    fn collect(
+        bufmgr: &BufferPoolManager,
+        meta_page_id: PageId,
+        snapshot: &Snapshot,
+    ) -> Vec<Vec<u8>> {
+        let mut iter = BTree::new(meta_page_id)
+            .search(bufmgr, SearchMode::Start)
+            .unwrap();
+        let mut keys = vec![];
+        while let Some((key, _)) = iter
+            .advance_visible(bufmgr, Direction::Forward, snapshot)
+            .unwrap()
+        {
+            keys.push(key);
+        }
+        keys
+    }
+
+    fn pkeys(pkeys: &[&[u8]]) -> Vec<Vec<u8>> {
+        pkeys
+            .iter()
+            .map(|pkey| {
+                let mut key = vec![];
+                tuple::encode([pkey].iter(), &mut key);
+                key
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_transaction() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"z", b"Alice", b"Smith"])
+            .unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"x", b"Bob", b"Johnson"])
+            .unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"y", b"Charlie", b"Williams"])
+            .unwrap();
+        table.delete(&bufmgr, &mut txn, &[b"z"]).unwrap();
+        assert!(table
+            .insert(&bufmgr, &mut txn, &[b"w", b"Dave", b"Johnson"])
+            .is_err());
+        assert_eq!(
+            pkeys(&[b"x", b"y"]),
+            collect(&bufmgr, table.meta_page_id, txn.snapshot())
+        );
+        txn.rollback(&bufmgr).unwrap();
+
+        let txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert_eq!(
+            pkeys(&[b"x", b"z"]),
+            collect(&bufmgr, table.meta_page_id, txn.snapshot())
+        );
+        assert_eq!(
+            2,
+            collect(
+                &bufmgr,
+                table.unique_indices[0].meta_page_id,
+                txn.snapshot()
+            )
+            .len()
+        );
+        txn.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let meta_page_ids = (table.meta_page_id, table.unique_indices[0].meta_page_id);
+        let records: Vec<&[&[u8]]> = vec![&[b"z", b"Alice", b"Smith"], &[b"x", b"Bob", b"Johnson"]];
+        table.bulk_load(&bufmgr, &records, 90).unwrap();
+        assert_eq!(
+            meta_page_ids,
+            (table.meta_page_id, table.unique_indices[0].meta_page_id)
+        );
+
+        let txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert_eq!(
+            pkeys(&[b"x", b"z"]),
+            collect(&bufmgr, table.meta_page_id, txn.snapshot())
+        );
+        assert_eq!(
+            2,
+            collect(
+                &bufmgr,
+                table.unique_indices[0].meta_page_id,
+                txn.snapshot()
+            )
+            .len()
+        );
+        txn.commit(&bufmgr).unwrap();
+
+        let err = table.bulk_load(&bufmgr, &records, 90).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<btree::Error>(),
+            Some(btree::Error::NotEmpty)
+        ));
+    }
+
+    #[test]
+    fn test_insert_atomic() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"z", b"Alice", b"Smith"])
+            .unwrap();
+        let long_name = vec![b'a'; 3000];
+        assert!(table
+            .insert(&bufmgr, &mut txn, &[b"y", b"Bob", &long_name])
+            .is_err());
+        assert_eq!(
+            1,
+            collect(&bufmgr, table.meta_page_id, txn.snapshot()).len()
+        );
+        assert_eq!(
+            1,
+            collect(
+                &bufmgr,
+                table.unique_indices[0].meta_page_id,
+                txn.snapshot()
+            )
+            .len()
+        );
+        txn.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"z", b"Alice", b"Smith"])
+            .unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        let reader = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        let mut writer = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut writer, &[b"y", b"Bob", b"Johnson"])
+            .unwrap();
+        table.delete(&bufmgr, &mut writer, &[b"z"]).unwrap();
+
+        // `other` starts before `writer` commits; once the row locks are
+        // released it must still not see or overwrite the committed changes
+        let mut other = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        writer.commit(&bufmgr).unwrap();
+        let err = table.delete(&bufmgr, &mut other, &[b"y"]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<btree::Error>(),
+            Some(btree::Error::KeyNotFound)
+        ));
+        let err = table
+            .insert(&bufmgr, &mut other, &[b"z", b"Eve", b"Brown"])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<transaction::Error>(),
+            Some(transaction::Error::WriteConflict)
+        ));
+        other.rollback(&bufmgr).unwrap();
+
+        assert_eq!(
+            pkeys(&[b"z"]),
+            collect(&bufmgr, table.meta_page_id, reader.snapshot())
+        );
+        reader.commit(&bufmgr).unwrap();
+        let reader = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert_eq!(
+            pkeys(&[b"y"]),
+            collect(&bufmgr, table.meta_page_id, reader.snapshot())
+        );
+        reader.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_dropped_transaction() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"z", b"Alice", b"Smith"])
+            .unwrap();
+        drop(txn);
+
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert!(collect(&bufmgr, table.meta_page_id, txn.snapshot()).is_empty());
+        // the aborted row does not conflict with a new one
+        table
+            .insert(&bufmgr, &mut txn, &[b"z", b"Bob", b"Smith"])
+            .unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        let txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert_eq!(
+            pkeys(&[b"z"]),
+            collect(&bufmgr, table.meta_page_id, txn.snapshot())
+        );
+        txn.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_unfinished_transaction_after_restart() {
+        let dir = tempdir().unwrap();
+        let data_file_path = dir.path().join("test.rly");
+        let open = || {
+            let disk = DiskManager::open(&data_file_path).unwrap();
+            let bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+            let txn_mgr = TransactionManager::open(&bufmgr).unwrap();
+            (bufmgr, txn_mgr)
+        };
+        let (bufmgr, txn_mgr) = open();
+        let mut table = Table {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            unique_indices: vec![],
+        };
+        table.create(&bufmgr).unwrap();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"x", b"Alice", b"Smith"])
+            .unwrap();
+        txn.commit(&bufmgr).unwrap();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"y", b"Bob", b"Johnson"])
+            .unwrap();
+        table.delete(&bufmgr, &mut txn, &[b"x"]).unwrap();
+        // the process stops before the transaction ends
+        drop(bufmgr);
+        std::mem::forget(txn);
+
+        let (bufmgr, txn_mgr) = open();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert_eq!(
+            pkeys(&[b"x"]),
+            collect(&bufmgr, table.meta_page_id, txn.snapshot())
+        );
+        table
+            .insert(&bufmgr, &mut txn, &[b"y", b"Charlie", b"Williams"])
+            .unwrap();
+        table.delete(&bufmgr, &mut txn, &[b"x"]).unwrap();
+        txn.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_unique_key_reuse() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let bufmgr = Arc::new(bufmgr);
+        let table = Arc::new(table);
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut txn, &[b"x", b"Alice", b"Smith"])
+            .unwrap();
+        txn.commit(&bufmgr).unwrap();
+        let mut txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table.delete(&bufmgr, &mut txn, &[b"x"]).unwrap();
+        txn.commit(&bufmgr).unwrap();
+
+        // both find the index entry for Smith deleted and reuse it
+        let mut first = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        let mut second = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut first, &[b"y", b"Bob", b"Smith"])
+            .unwrap();
+        let handle = {
+            let bufmgr = Arc::clone(&bufmgr);
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                let result = table.insert(&bufmgr, &mut second, &[b"z", b"Eve", b"Smith"]);
+                second.rollback(&bufmgr).unwrap();
+                result
+            })
+        };
+        // the second waits for the first to end instead of checking the
+        // index entry while it is being rewritten
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+        first.commit(&bufmgr).unwrap();
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<transaction::Error>(),
+            Some(transaction::Error::WriteConflict)
+        ));
+
+        let txn = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        assert_eq!(
+            pkeys(&[b"y"]),
+            collect(&bufmgr, table.meta_page_id, txn.snapshot())
+        );
+        assert_eq!(
+            1,
+            collect(
+                &bufmgr,
+                table.unique_indices[0].meta_page_id,
+                txn.snapshot()
+            )
+            .len()
+        );
+        txn.commit(&bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let (bufmgr, txn_mgr, table) = setup();
+        let bufmgr = Arc::new(bufmgr);
+        let table = Arc::new(table);
+        let mut older = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        let mut younger = Transaction::begin(&txn_mgr, &bufmgr).unwrap();
+        table
+            .insert(&bufmgr, &mut older, &[b"x", b"Alice", b"Smith"])
+            .unwrap();
+        table
+            .insert(&bufmgr, &mut younger, &[b"y", b"Bob", b"Johnson"])
+            .unwrap();
+        let handle = {
+            let bufmgr = Arc::clone(&bufmgr);
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                let result = table.insert(&bufmgr, &mut younger, &[b"x", b"Eve", b"Brown"]);
+                younger.rollback(&bufmgr).unwrap();
+                result
+            })
+        };
+        table
+            .insert(&bufmgr, &mut older, &[b"y", b"Dave", b"Williams"])
+            .unwrap();
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<transaction::Error>(),
+            Some(transaction::Error::Lock(lock::Error::Deadlock(_)))
+        ));
+        older.commit(&bufmgr).unwrap();
+    }
 }
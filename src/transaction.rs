@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use thiserror::Error;
+
+use crate::btree::{self, BTree, ConflictPolicy, SearchMode};
+use crate::buffer::{self, BufferPoolManager};
+use crate::disk::PageId;
+use crate::lock::{self, LockManager, LockMode};
+use crate::mvcc::{self, CommitLog, Snapshot, Version, Xid, FIRST_NORMAL_XID, INVALID_XID};
+
+// xids are reserved in batches so that the superblock is not rewritten on
+// every begin; the unused rest of a batch is skipped after a restart.
+const XID_BATCH_SIZE: u64 = 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("write conflict with a concurrent transaction")]
+    WriteConflict,
+    #[error(transparent)]
+    Lock(#[from] lock::Error),
+    #[error(transparent)]
+    Btree(#[from] btree::Error),
+    #[error(transparent)]
+    Buffer(#[from] buffer::Error),
+}
+
+#[derive(Debug)]
+struct State {
+    next_xid: Xid,
+    xid_limit: Xid,
+    // active xid -> the oldest xid that may still be undecided for it
+    active: BTreeMap<Xid, Xid>,
+}
+
+// The commit log is kept in memory and persisted in a B-tree of chunks, which
+// is read back in full on open.
+#[derive(Debug)]
+pub struct TransactionManager {
+    state: Mutex<State>,
+    locks: LockManager,
+    commits: Arc<CommitLog>,
+    clog_meta_page_id: PageId,
+    // chunks are rewritten whole, so commits update them one at a time
+    clog_latch: Mutex<()>,
+}
+
+impl TransactionManager {
+    pub fn open(bufmgr: &BufferPoolManager) -> Result<Arc<Self>, Error> {
+        let next_xid = bufmgr.next_xid().max(FIRST_NORMAL_XID);
+        let clog = match bufmgr.clog_page_id() {
+            Some(meta_page_id) => BTree::new(meta_page_id),
+            None => {
+                let clog = BTree::create(bufmgr)?;
+                bufmgr.set_clog_page_id(clog.meta_page_id)?;
+                clog
+            }
+        };
+        let commits = CommitLog::default();
+        let mut iter = clog.search(bufmgr, SearchMode::Start)?;
+        while let Some((key, chunk)) = iter.next(bufmgr)? {
+            let mut chunk_id = [0u8; 8];
+            chunk_id.copy_from_slice(&key);
+            commits.load_chunk(u64::from_be_bytes(chunk_id), &chunk);
+        }
+        Ok(Arc::new(Self {
+            state: Mutex::new(State {
+                next_xid,
+                xid_limit: next_xid,
+                active: BTreeMap::new(),
+            }),
+            locks: LockManager::new(),
+            commits: Arc::new(commits),
+            clog_meta_page_id: clog.meta_page_id,
+            clog_latch: Mutex::new(()),
+        }))
+    }
+
+    fn begin(&self, bufmgr: &BufferPoolManager) -> Result<Snapshot, buffer::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.next_xid == state.xid_limit {
+            bufmgr.set_next_xid(state.xid_limit + XID_BATCH_SIZE)?;
+            state.xid_limit += XID_BATCH_SIZE;
+        }
+        let xid = state.next_xid;
+        state.next_xid += 1;
+        let active: Vec<_> = state.active.keys().copied().collect();
+        let horizon = active.first().copied().unwrap_or(xid);
+        state.active.insert(xid, horizon);
+        Ok(Snapshot {
+            xid,
+            xmax: xid + 1,
+            active,
+            commits: Arc::clone(&self.commits),
+        })
+    }
+
+    // The commit bit is on disk before anybody can see it.
+    fn commit(&self, bufmgr: &BufferPoolManager, xid: Xid) -> Result<(), Error> {
+        let _clog = self.clog_latch.lock().unwrap();
+        let (chunk_id, chunk) = self.commits.committed_chunk(xid);
+        BTree::new(self.clog_meta_page_id).upsert(
+            bufmgr,
+            &chunk_id.to_be_bytes(),
+            &chunk,
+            ConflictPolicy::Replace,
+        )?;
+        bufmgr.sync_log()?;
+        self.commits.set_committed(xid);
+        Ok(())
+    }
+
+    fn finish(&self, xid: Xid) {
+        self.state.lock().unwrap().active.remove(&xid);
+    }
+
+    // A transaction that is neither running nor committed has aborted. A
+    // committing transaction sets its commit bit before it stops running, so
+    // the order of the checks matters.
+    fn is_aborted(&self, xid: Xid) -> bool {
+        xid >= FIRST_NORMAL_XID
+            && !self.state.lock().unwrap().active.contains_key(&xid)
+            && !self.commits.is_committed(xid)
+    }
+
+    // Every transaction older than the horizon has finished and is visible
+    // to all active snapshots.
+    fn horizon(&self) -> Xid {
+        let state = self.state.lock().unwrap();
+        state
+            .active
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(state.next_xid)
+    }
+}
+
+#[derive(Debug)]
+enum Undo {
+    Insert {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+    },
+    Update {
+        meta_page_id: PageId,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Savepoint(usize);
+
+// Writes add versions stamped with the transaction's xid and are recorded in
+// an in-memory undo log, which rollback replays in reverse. Reads only see
+// versions visible to the snapshot taken at begin.
+//
+// The undo log is not made durable. Each write reaches the trees as its own
+// logged operation, so after a crash the versions of an unfinished
+// transaction are still on disk; only the missing commit bit keeps them
+// invisible until a later writer discards them. Rollback and savepoints undo
+// physically, but a crash in the middle of one leaves the rest to the commit
+// log in the same way.
+#[derive(Debug)]
+#[must_use = "a transaction must be committed or rolled back"]
+pub struct Transaction {
+    manager: Arc<TransactionManager>,
+    snapshot: Snapshot,
+    undo_log: Vec<Undo>,
+}
+
+impl Transaction {
+    pub fn begin(
+        manager: &Arc<TransactionManager>,
+        bufmgr: &BufferPoolManager,
+    ) -> Result<Self, buffer::Error> {
+        let snapshot = manager.begin(bufmgr)?;
+        Ok(Self {
+            manager: Arc::clone(manager),
+            snapshot,
+            undo_log: vec![],
+        })
+    }
+
+    pub fn xid(&self) -> Xid {
+        self.snapshot.xid
+    }
+
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    // Row locks are held until the transaction ends, so a row written by one
+    // transaction is not read or written by another until it commits or
+    // rolls back.
+    pub fn lock(&self, meta_page_id: PageId, key: &[u8], mode: LockMode) -> Result<(), Error> {
+        self.manager
+            .locks
+            .lock(self.xid(), (meta_page_id, key.to_vec()), mode)?;
+        Ok(())
+    }
+
+    pub fn get(
+        &self,
+        bufmgr: &BufferPoolManager,
+        btree: &BTree,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        Ok(btree
+            .get(bufmgr, key)?
+            .and_then(|bytes| self.snapshot.visible_value(&bytes)))
+    }
+
+    // Snapshot isolation: the newest version may only be overwritten if it
+    // was written by us or by a transaction our snapshot sees.
+    fn check_write(&self, head: &Version) -> Result<(), Error> {
+        for &xid in &[head.xmin, head.xmax] {
+            if xid != INVALID_XID && !self.snapshot.sees(xid) {
+                return Err(Error::WriteConflict);
+            }
+        }
+        Ok(())
+    }
+
+    // The versions in `bytes`, less the changes of aborted transactions,
+    // which a crash or a dropped transaction leaves behind. Those only ever
+    // sit at the head of the chain.
+    fn live_versions(&self, bytes: &[u8]) -> Vec<Version> {
+        let mut versions = mvcc::decode(bytes);
+        while versions
+            .first()
+            .is_some_and(|head| self.manager.is_aborted(head.xmin))
+        {
+            versions.remove(0);
+        }
+        if let Some(head) = versions.first_mut() {
+            if self.manager.is_aborted(head.xmax) {
+                head.xmax = INVALID_XID;
+            }
+        }
+        versions
+    }
+
+    // A key may be inserted again once its newest version is deleted.
+    fn check_reinsert(&self, versions: &[Version]) -> Result<(), Error> {
+        if let Some(head) = versions.first() {
+            self.check_write(head)?;
+            if head.xmax == INVALID_XID {
+                return Err(btree::Error::DuplicateKey.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Every check or change of a version chain locks its key first, so that
+    // no other transaction writes the chain in between. Unique index entries
+    // need this as much as rows do.
+    pub fn check_insert(
+        &self,
+        bufmgr: &BufferPoolManager,
+        btree: &BTree,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        self.lock(btree.meta_page_id, key, LockMode::Exclusive)?;
+        match btree.get(bufmgr, key)? {
+            Some(bytes) => {
+                self.check_reinsert(&self.live_versions(&bytes))?;
+                Ok(Some(bytes))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn prune(&self, versions: &mut Vec<Version>) {
+        let horizon = self.manager.horizon();
+        if let Some(idx) = versions.iter().position(|version| version.xmin < horizon) {
+            let is_dead = versions[idx].xmax != INVALID_XID && versions[idx].xmax < horizon;
+            versions.truncate(if is_dead { idx } else { idx + 1 });
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        btree: &BTree,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let new_version = Version {
+            xmin: self.xid(),
+            xmax: INVALID_XID,
+            value: value.to_vec(),
+        };
+        self.lock(btree.meta_page_id, key, LockMode::Exclusive)?;
+        let undo = match btree.get(bufmgr, key)? {
+            None => {
+                btree.insert(bufmgr, key, &mvcc::encode(&[new_version]))?;
+                Undo::Insert {
+                    meta_page_id: btree.meta_page_id,
+                    key: key.to_vec(),
+                }
+            }
+            Some(bytes) => {
+                let mut versions = self.live_versions(&bytes);
+                self.check_reinsert(&versions)?;
+                self.prune(&mut versions);
+                versions.insert(0, new_version);
+                btree.update(bufmgr, key, &mvcc::encode(&versions))?;
+                Undo::Update {
+                    meta_page_id: btree.meta_page_id,
+                    key: key.to_vec(),
+                    value: bytes,
+                }
+            }
+        };
+        self.undo_log.push(undo);
+        Ok(())
+    }
+
+    pub fn delete(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        btree: &BTree,
+        key: &[u8],
+    ) -> Result<(), Error> {
+        self.lock(btree.meta_page_id, key, LockMode::Exclusive)?;
+        let bytes = btree.get(bufmgr, key)?.ok_or(btree::Error::KeyNotFound)?;
+        let mut versions = self.live_versions(&bytes);
+        let head = versions.first().ok_or(btree::Error::KeyNotFound)?;
+        self.check_write(head)?;
+        if head.xmax != INVALID_XID {
+            return Err(btree::Error::KeyNotFound.into());
+        }
+        versions[0].xmax = self.xid();
+        self.prune(&mut versions);
+        btree.update(bufmgr, key, &mvcc::encode(&versions))?;
+        self.undo_log.push(Undo::Update {
+            meta_page_id: btree.meta_page_id,
+            key: key.to_vec(),
+            value: bytes,
+        });
+        Ok(())
+    }
+
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint(self.undo_log.len())
+    }
+
+    pub fn rollback_to(
+        &mut self,
+        bufmgr: &BufferPoolManager,
+        savepoint: Savepoint,
+    ) -> Result<(), Error> {
+        while self.undo_log.len() > savepoint.0 {
+            match self.undo_log.pop().unwrap() {
+                Undo::Insert { meta_page_id, key } => {
+                    BTree::new(meta_page_id).delete(bufmgr, &key)?;
+                }
+                Undo::Update {
+                    meta_page_id,
+                    key,
+                    value,
+                } => {
+                    BTree::new(meta_page_id).update(bufmgr, &key, &value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn commit(self, bufmgr: &BufferPoolManager) -> Result<(), Error> {
+        // a transaction that wrote nothing has nothing to make visible
+        if self.undo_log.is_empty() {
+            return Ok(());
+        }
+        self.manager.commit(bufmgr, self.xid())
+    }
+
+    pub fn rollback(mut self, bufmgr: &BufferPoolManager) -> Result<(), Error> {
+        self.rollback_to(bufmgr, Savepoint(0))
+    }
+}
+
+// A transaction dropped before it committed has aborted: without a commit
+// bit its versions stay invisible, and later writers discard them.
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.manager.finish(self.xid());
+        self.manager.locks.release_all(self.xid());
+    }
+}
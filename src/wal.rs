@@ -0,0 +1,306 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+
+use crate::disk::{self, PageId, PAGE_SIZE};
+
+const MAGIC: [u8; 8] = *b"RELLYWAL";
+
+// The magic, the lsn of the first record in the file and the lsn recovery
+// starts redoing at.
+const HEADER_SIZE: u64 = 8 + 8 + 8;
+
+const RECORD_PAGE: u8 = 1;
+const RECORD_COMMIT: u8 = 2;
+
+// Position of a record in the log. Lsns keep growing when the space before
+// a checkpoint is reclaimed, so the lsn of a record is not its file offset.
+pub type Lsn = u64;
+
+enum Record {
+    Page(PageId, Vec<u8>),
+    Commit,
+}
+
+// Each record is a kind byte, its own lsn and a page id, followed by a full
+// page image for page records. Page images are logged in groups terminated
+// by a commit record, and recovery only redoes complete groups. A checkpoint
+// stores the lsn of a group boundary before which every group has reached
+// the heap file in the header, so recovery starts redoing there, and the
+// space before it is reused.
+pub struct Wal {
+    file: File,
+    is_synced: bool,
+    start_lsn: Lsn,
+    redo_lsn: Lsn,
+    // the log is only appended to after recovery has truncated it
+    len: Lsn,
+}
+
+impl Wal {
+    pub fn open(mut file: File) -> io::Result<Self> {
+        let file_len = file.metadata()?.len();
+        if file_len < HEADER_SIZE {
+            let mut wal = Self {
+                file,
+                is_synced: true,
+                start_lsn: 0,
+                redo_lsn: 0,
+                len: 0,
+            };
+            wal.write_header()?;
+            return Ok(wal);
+        }
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a relly log file",
+            ));
+        }
+        let start_lsn = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let redo_lsn = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        Ok(Self {
+            file,
+            is_synced: true,
+            start_lsn,
+            redo_lsn,
+            len: start_lsn + file_len - HEADER_SIZE,
+        })
+    }
+
+    fn offset(&self, lsn: Lsn) -> u64 {
+        HEADER_SIZE + lsn - self.start_lsn
+    }
+
+    // Syncs the header and leaves the file positioned at the end of the log.
+    fn write_header(&mut self) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&self.start_lsn.to_le_bytes());
+        header.extend_from_slice(&self.redo_lsn.to_le_bytes());
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.sync_all()?;
+        self.file.seek(SeekFrom::Start(self.offset(self.len)))?;
+        Ok(())
+    }
+
+    fn append(&mut self, kind: u8, id: u64, page: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(1 + 8 + 8 + page.len());
+        record.push(kind);
+        record.extend_from_slice(&self.len.to_le_bytes());
+        record.extend_from_slice(&id.to_le_bytes());
+        record.extend_from_slice(page);
+        self.file.write_all(&record)?;
+        self.len += record.len() as u64;
+        self.is_synced = false;
+        Ok(())
+    }
+
+    pub fn append_page(&mut self, page_id: PageId, page: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(PAGE_SIZE, page.len());
+        self.append(RECORD_PAGE, page_id.to_u64(), page)
+    }
+
+    pub fn append_commit(&mut self) -> io::Result<()> {
+        self.append(RECORD_COMMIT, PageId::INVALID_PAGE_ID.to_u64(), &[])
+    }
+
+    // The lsn the next record will be written at.
+    pub fn lsn(&self) -> Lsn {
+        self.len
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if !self.is_synced {
+            self.file.sync_data()?;
+            self.is_synced = true;
+        }
+        Ok(())
+    }
+
+    // Makes recovery start at `redo_lsn`. The records from there on are then
+    // moved to the start of the file if they fit before `redo_lsn`, so that
+    // none of them is overwritten while the header still points at it;
+    // otherwise the space is reclaimed at a later checkpoint.
+    pub fn checkpoint(&mut self, redo_lsn: Lsn) -> io::Result<()> {
+        // a checkpoint begun before the log was truncated has nothing to keep
+        let redo_lsn = redo_lsn.max(self.start_lsn);
+        self.sync()?;
+        self.redo_lsn = redo_lsn;
+        self.write_header()?;
+        let kept = self.len - redo_lsn;
+        let reclaimed = redo_lsn - self.start_lsn;
+        if reclaimed == 0 || kept > reclaimed {
+            return Ok(());
+        }
+        let mut buf = vec![0u8; PAGE_SIZE];
+        let mut copied = 0;
+        while copied < kept {
+            let chunk = &mut buf[..(kept - copied).min(PAGE_SIZE as u64) as usize];
+            self.file
+                .seek(SeekFrom::Start(self.offset(redo_lsn + copied)))?;
+            self.file.read_exact(chunk)?;
+            self.file.seek(SeekFrom::Start(HEADER_SIZE + copied))?;
+            self.file.write_all(chunk)?;
+            copied += chunk.len() as u64;
+        }
+        self.file.sync_data()?;
+        // whatever lies past the moved records until the file is shrunk
+        // carries lsns that do not match its new position, and ends the log
+        self.start_lsn = redo_lsn;
+        self.write_header()?;
+        self.file.set_len(HEADER_SIZE + kept)?;
+        self.file.sync_all()
+    }
+
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(HEADER_SIZE)?;
+        self.start_lsn = self.len;
+        self.redo_lsn = self.len;
+        self.write_header()?;
+        self.is_synced = true;
+        Ok(())
+    }
+
+    // Calls `f` with the records from `lsn` on. A torn or corrupted record,
+    // or one written at another lsn, ends the log.
+    fn read_records(
+        &mut self,
+        mut lsn: Lsn,
+        mut f: impl FnMut(Record) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.offset(lsn)))?;
+        let mut reader = BufReader::new(&self.file);
+        loop {
+            let mut header = [0u8; 17];
+            if reader.read_exact(&mut header).is_err() {
+                return Ok(());
+            }
+            if u64::from_le_bytes(header[1..9].try_into().unwrap()) != lsn {
+                return Ok(());
+            }
+            let id = u64::from_le_bytes(header[9..].try_into().unwrap());
+            let record = match header[0] {
+                RECORD_PAGE => {
+                    let mut page = vec![0u8; PAGE_SIZE];
+                    if reader.read_exact(&mut page).is_err() || !disk::verify_checksum(&page) {
+                        return Ok(());
+                    }
+                    lsn += PAGE_SIZE as u64;
+                    Record::Page(PageId(id), page)
+                }
+                RECORD_COMMIT => Record::Commit,
+                _ => return Ok(()),
+            };
+            lsn += header.len() as u64;
+            f(record)?;
+        }
+    }
+
+    // Redoes every committed group in log order, starting at the last
+    // checkpoint. A torn or corrupted record ends the log, discarding the
+    // group it belongs to. Returns the number of groups redone.
+    pub fn replay(
+        &mut self,
+        mut redo: impl FnMut(PageId, &[u8]) -> io::Result<()>,
+    ) -> io::Result<usize> {
+        let mut group: Vec<(PageId, Vec<u8>)> = vec![];
+        let mut num_groups = 0;
+        let result = self.read_records(self.redo_lsn, |record| {
+            match record {
+                Record::Page(page_id, page) => group.push((page_id, page)),
+                Record::Commit => {
+                    for (page_id, page) in group.drain(..) {
+                        redo(page_id, &page)?;
+                    }
+                    num_groups += 1;
+                }
+            }
+            Ok(())
+        });
+        self.file.seek(SeekFrom::Start(self.offset(self.len)))?;
+        result.map(|_| num_groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::*;
+
+    fn page(byte: u8) -> Vec<u8> {
+        let mut page = vec![byte; PAGE_SIZE];
+        disk::set_checksum(&mut page);
+        page
+    }
+
+    fn replay(wal: &mut Wal) -> Vec<PageId> {
+        let mut redone = vec![];
+        wal.replay(|page_id, _| {
+            redone.push(page_id);
+            Ok(())
+        })
+        .unwrap();
+        redone
+    }
+
+    #[test]
+    fn test_replay_from_checkpoint() {
+        let mut wal = Wal::open(tempfile().unwrap()).unwrap();
+        wal.append_page(PageId(1), &page(1)).unwrap();
+        wal.append_commit().unwrap();
+        let redo_lsn = wal.lsn();
+        wal.append_page(PageId(2), &page(2)).unwrap();
+        wal.append_commit().unwrap();
+        wal.append_page(PageId(5), &page(5)).unwrap();
+        wal.append_commit().unwrap();
+        // the two groups after `redo_lsn` do not fit before it
+        wal.checkpoint(redo_lsn).unwrap();
+        wal.append_page(PageId(3), &page(3)).unwrap();
+        wal.append_commit().unwrap();
+        // a group without its commit record is not redone
+        wal.append_page(PageId(4), &page(4)).unwrap();
+
+        let mut reopened = Wal::open(wal.file.try_clone().unwrap()).unwrap();
+        assert_eq!(vec![PageId(2), PageId(5), PageId(3)], replay(&mut reopened));
+    }
+
+    #[test]
+    fn test_checkpoint_reclaims_log() {
+        let mut file = tempfile().unwrap();
+        let mut wal = Wal::open(file.try_clone().unwrap()).unwrap();
+        for page_id in 1..=3 {
+            wal.append_page(PageId(page_id), &page(page_id as u8))
+                .unwrap();
+            wal.append_commit().unwrap();
+        }
+        let redo_lsn = wal.lsn();
+        wal.append_page(PageId(4), &page(4)).unwrap();
+        wal.append_commit().unwrap();
+        let mut before = vec![];
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut before).unwrap();
+
+        wal.checkpoint(redo_lsn).unwrap();
+        let group_len = wal.lsn() - redo_lsn;
+        assert_eq!(HEADER_SIZE + group_len, file.metadata().unwrap().len());
+        wal.append_page(PageId(5), &page(5)).unwrap();
+        wal.append_commit().unwrap();
+        let mut reopened = Wal::open(file.try_clone().unwrap()).unwrap();
+        assert_eq!(vec![PageId(4), PageId(5)], replay(&mut reopened));
+
+        // a crash before the file was shrunk leaves the old records behind
+        // the moved ones
+        file.set_len(HEADER_SIZE + group_len).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&before[(HEADER_SIZE + group_len) as usize..])
+            .unwrap();
+        let mut reopened = Wal::open(file.try_clone().unwrap()).unwrap();
+        assert_eq!(vec![PageId(4)], replay(&mut reopened));
+    }
+}